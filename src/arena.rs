@@ -0,0 +1,230 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    mem::size_of,
+};
+
+use deepsize::DeepSizeOf;
+
+use crate::{initial_context, ContextSize};
+
+// Index of a context in the arena, small enough that handles are cheaper than
+// pointers. The largest value marks an empty slot in the index table.
+pub trait ArenaIndex: Copy + Eq + Hash + DeepSizeOf {
+    const EMPTY: Self;
+    // Contexts an arena indexed by this can hold
+    const CAPACITY: usize;
+    fn from_usize(index: usize) -> Option<Self>;
+    fn to_usize(self) -> usize;
+}
+
+macro_rules! arena_index {
+    ($type:ty) => {
+        impl ArenaIndex for $type {
+            const EMPTY: Self = <$type>::MAX;
+            const CAPACITY: usize = <$type>::MAX as usize;
+
+            fn from_usize(index: usize) -> Option<Self> {
+                <$type>::try_from(index).ok().filter(|&index| index != Self::EMPTY)
+            }
+
+            fn to_usize(self) -> usize {
+                self as usize
+            }
+        }
+    };
+}
+
+arena_index!(u16);
+arena_index!(u32);
+
+// Unique contexts stored one after another, found again through an open
+// addressing table of indices into them. The table only holds indices, so
+// looking a context up compares against the arena rather than a second copy.
+#[derive(Clone, Debug, DeepSizeOf)]
+pub struct Arena<Context, Index> {
+    contexts: Vec<Context>,
+    // Power of two sized, EMPTY where there's no context
+    table: Vec<Index>,
+}
+
+impl<Context, Index> Default for Arena<Context, Index> {
+    fn default() -> Self {
+        Arena {
+            contexts: vec![],
+            table: vec![],
+        }
+    }
+}
+
+fn hash<T: Hash>(value: &T) -> usize {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+impl<Context, Index> Arena<Context, Index>
+where
+    Context: Eq + Hash,
+    Index: ArenaIndex,
+{
+    // Slot in the table that holds the context, or the empty one it would go in
+    fn find_slot(&self, context: &Context) -> usize {
+        let mask = self.table.len() - 1;
+        let mut slot = hash(context) & mask;
+        loop {
+            let index = self.table[slot];
+            if index == Index::EMPTY || self.contexts[index.to_usize()] == *context {
+                return slot;
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+
+    // Keep the table at most 7/8 full, like hashbrown
+    fn grow(&mut self) {
+        let capacity = (self.table.len() * 2).max(8);
+        self.table = vec![Index::EMPTY; capacity];
+        for (i, context) in self.contexts.iter().enumerate() {
+            let mut slot = hash(context) & (capacity - 1);
+            while self.table[slot] != Index::EMPTY {
+                slot = (slot + 1) & (capacity - 1);
+            }
+            self.table[slot] = Index::from_usize(i).unwrap();
+        }
+    }
+
+    pub fn intern(&mut self, context: Context) -> Index {
+        if (self.contexts.len() + 1) * 8 > self.table.len() * 7 {
+            self.grow();
+        }
+        let slot = self.find_slot(&context);
+        if self.table[slot] == Index::EMPTY {
+            let index = Index::from_usize(self.contexts.len()).expect("arena index overflowed");
+            self.contexts.push(context);
+            self.table[slot] = index;
+        }
+        self.table[slot]
+    }
+
+    pub fn get(&self, index: Index) -> &Context {
+        &self.contexts[index.to_usize()]
+    }
+}
+
+
+// Handles are Index sized, with the contexts and their index table as storage
+pub struct ArenaStorage<Context, Index = u32> {
+    arena: Arena<Context, Index>,
+}
+
+impl<Context, Index> Default for ArenaStorage<Context, Index> {
+    fn default() -> Self {
+        ArenaStorage {
+            arena: Arena::default(),
+        }
+    }
+}
+
+impl<Context, Index> ContextSize for ArenaStorage<Context, Index>
+where
+    Context: DeepSizeOf + Eq + Hash + Clone + Default + Into<initial_context::Context> + ContextSize<Pointer = Context>,
+    Index: ArenaIndex,
+{
+    type Context = Context;
+    type Pointer = Index;
+    type Storage = Arena<Context, Index>;
+
+    const CAPACITY: usize = Index::CAPACITY;
+
+    fn get_storage(&self) -> Option<Arena<Context, Index>> {
+        Some(self.arena.clone())
+    }
+
+    // The clone from get_storage would leave out the spare capacity
+    fn get_storage_size(&self) -> usize {
+        self.arena.deep_size_of()
+    }
+
+    fn store_context(&mut self, context: Context) -> Index {
+        self.arena.intern(context)
+    }
+
+    fn load_context(&self, pointer: &Index) -> initial_context::Context {
+        self.arena.get(*pointer).clone().into()
+    }
+
+    fn holds(context: &initial_context::Context) -> bool {
+        Context::holds(context)
+    }
+
+    fn get_analytic_storage_size(&self) -> usize {
+        // Contexts sit inline in the arena, anything they own on the heap is extra
+        let heap: usize = self.arena.contexts.iter().map(|context| {
+            Context::default().get_analytic_pointer_size(context) - size_of::<Context>()
+        }).sum();
+        self.arena.contexts.capacity() * size_of::<Context>() + heap + self.arena.table.capacity() * size_of::<Index>()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compact_temp_mapping, synthetic::test_contexts};
+
+    #[test]
+    fn growing_keeps_every_index() {
+        let mut arena: Arena<u64, u32> = Arena::default();
+        let indices: Vec<u32> = (0..10_000u64).map(|value| arena.intern(value * 7919)).collect();
+
+        // Every grow rehashes into a table twice the size, at most 7/8 full
+        assert!(arena.table.len().is_power_of_two());
+        assert!(arena.contexts.len() * 8 <= arena.table.len() * 7);
+        assert_eq!(arena.table.iter().filter(|&&index| index != u32::EMPTY).count(), 10_000);
+
+        for (value, &index) in (0..10_000u64).zip(indices.iter()) {
+            assert_eq!(index.to_usize(), value as usize);
+            assert_eq!(*arena.get(index), value * 7919);
+            assert_eq!(arena.intern(value * 7919), index);
+        }
+    }
+
+    #[test]
+    fn duplicates_are_interned_once() {
+        let mut arena: Arena<u64, u32> = Arena::default();
+        let values = [3, 1, 3, 3, 2, 1, 2];
+        let indices: Vec<u32> = values.iter().map(|&value| arena.intern(value)).collect();
+        assert_eq!(indices, [0, 1, 0, 0, 2, 1, 2]);
+        assert_eq!(arena.contexts, [3, 1, 2]);
+    }
+
+    #[test]
+    fn u16_arena_fills_up() {
+        let mut arena: Arena<u64, u16> = Arena::default();
+        for value in 0..u16::CAPACITY as u64 {
+            arena.intern(value);
+        }
+        assert_eq!(arena.intern(0), 0);
+        assert_eq!(arena.intern(u16::CAPACITY as u64 - 1), u16::MAX - 1);
+
+        // The index that would come next is the one marking empty slots
+        let full = std::panic::catch_unwind(move || arena.intern(u16::CAPACITY as u64));
+        assert!(full.is_err());
+    }
+
+    #[test]
+    fn storage_numbers_contexts_in_first_seen_order() {
+        let contexts = test_contexts(6, 3000);
+        let mut storage: ArenaStorage<compact_temp_mapping::Context> = ArenaStorage::default();
+        let mut first_seen: Vec<&initial_context::Context> = vec![];
+        for ctx in contexts.iter() {
+            let index = storage.store_context(ctx.clone().into()).to_usize();
+            if index == first_seen.len() {
+                first_seen.push(ctx);
+            }
+            assert_eq!(first_seen[index], ctx);
+        }
+        assert_eq!(storage.arena.contexts.len(), first_seen.len());
+    }
+}
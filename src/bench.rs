@@ -0,0 +1,137 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    hint::black_box,
+    time::Instant,
+};
+
+use crate::{initial_context::{self, ContextWithCount}, new_strategy, ContextSize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BenchConfig {
+    // Runs thrown away before timing, to warm up caches and the allocator
+    pub warmup: usize,
+    // Timed runs, the median of which is reported
+    pub runs: usize,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        BenchConfig { warmup: 1, runs: 5 }
+    }
+}
+
+// Nanoseconds per operation, where every context in the dataset is replayed
+// as many times as its count
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Timing {
+    // Operations in one run of each loop
+    pub operations: u64,
+    // store_context, including cloning the context passed in
+    pub insert_ns: f64,
+    // load_context from a handle back to an initial_context::Context
+    pub decode_ns: f64,
+    // Comparing two handles, half of them to an equal context
+    pub eq_ns: f64,
+    // Hashing a handle with DefaultHasher
+    pub hash_ns: f64,
+}
+
+fn per_op(start: Instant, operations: u64) -> f64 {
+    start.elapsed().as_nanos() as f64 / operations.max(1) as f64
+}
+
+fn hash_of<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn run_once<T>(contexts: &[ContextWithCount], values: &[T::Context]) -> Timing
+where
+    T: ContextSize + Default,
+{
+    let operations: u64 = contexts.iter().map(|context| context.count).sum();
+    let mut t: T = new_strategy(contexts);
+
+    // Keep the last handle of each context for the other loops
+    let start = Instant::now();
+    let mut handles = Vec::with_capacity(values.len());
+    for (context, value) in contexts.iter().zip(values.iter()) {
+        let mut handle = None;
+        for _ in 0..context.count {
+            handle = Some(black_box(t.store_context(value.clone())));
+        }
+        if let Some(handle) = handle {
+            handles.push((context.count, handle));
+        }
+    }
+    let insert_ns = per_op(start, operations);
+
+    let start = Instant::now();
+    for (count, handle) in handles.iter() {
+        for _ in 0..*count {
+            black_box(t.load_context(black_box(handle)));
+        }
+    }
+    let decode_ns = per_op(start, operations);
+
+    // A second handle to every context, which only dedup strategies make
+    // identical to the first
+    let again: Vec<T::Pointer> = contexts
+        .iter()
+        .zip(values.iter())
+        .filter(|(context, _)| context.count > 0)
+        .map(|(_, value)| t.store_context(value.clone()))
+        .collect();
+    let start = Instant::now();
+    for (i, (count, handle)) in handles.iter().enumerate() {
+        let next = &handles[(i + 1) % handles.len()].1;
+        for _ in 0..*count {
+            black_box(black_box(handle) == black_box(&again[i]));
+            black_box(black_box(handle) == black_box(next));
+        }
+    }
+    let eq_ns = per_op(start, operations * 2);
+
+    let start = Instant::now();
+    for (count, handle) in handles.iter() {
+        for _ in 0..*count {
+            black_box(hash_of(black_box(handle)));
+        }
+    }
+    let hash_ns = per_op(start, operations);
+
+    Timing {
+        operations,
+        insert_ns,
+        decode_ns,
+        eq_ns,
+        hash_ns,
+    }
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(f64::total_cmp);
+    values[values.len() / 2]
+}
+
+// Median of every timing over config.runs runs, each on a fresh strategy
+pub fn bench<T>(contexts: &[ContextWithCount], config: BenchConfig) -> Timing
+where
+    T: ContextSize + Default,
+    <T as ContextSize>::Context: From<initial_context::Context>,
+{
+    let values: Vec<T::Context> = contexts.iter().map(|context| context.context.clone().into()).collect();
+    for _ in 0..config.warmup {
+        run_once::<T>(contexts, &values);
+    }
+    let runs: Vec<Timing> = (0..config.runs.max(1)).map(|_| run_once::<T>(contexts, &values)).collect();
+    Timing {
+        operations: runs[0].operations,
+        insert_ns: median(runs.iter().map(|run| run.insert_ns).collect()),
+        decode_ns: median(runs.iter().map(|run| run.decode_ns).collect()),
+        eq_ns: median(runs.iter().map(|run| run.eq_ns).collect()),
+        hash_ns: median(runs.iter().map(|run| run.hash_ns).collect()),
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+};
+
+use crate::initial_context::{Context, ContextWithCount, TempMapping, Type, MAX_LOCAL_TYPES, MAX_TEMP_TYPES};
+
+// Layout, all integers little-endian:
+//
+// header:  magic (8 bytes), version (u16), MAX_LOCAL_TYPES (u8),
+//          MAX_TEMP_TYPES (u8), number of records (u64)
+// records: count (varint)
+//          flags (u8): bit 0 block id follows, bit 1 parent id follows
+//          block, parent (varint, when present)
+//          stack_size (varint), sp_offset (zigzag varint), chain_depth (u8)
+//          self_type (u8), then two types per byte for local_types and temp_types
+//          temp_mapping, one byte each: 0 MapToStack, 1 MapToSelf, 2 + i MapToLocal(i)
+pub const MAGIC: &[u8; 8] = b"YJITCTX\0";
+pub const VERSION: u16 = 1;
+
+const HAS_BLOCK: u8 = 1;
+const HAS_PARENT: u8 = 2;
+
+pub fn is_binary_dataset(prefix: &[u8]) -> bool {
+    prefix.starts_with(MAGIC)
+}
+
+fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return writer.write_all(&[byte]);
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn encode_temp_mapping(mapping: TempMapping) -> u8 {
+    match mapping {
+        TempMapping::MapToStack => 0,
+        TempMapping::MapToSelf => 1,
+        TempMapping::MapToLocal(i) => 2 + i,
+    }
+}
+
+fn write_types<W: Write>(writer: &mut W, types: &[Type]) -> io::Result<()> {
+    for pair in types.chunks(2) {
+        let high = pair.get(1).map_or(0, |&ty| ty as u8);
+        writer.write_all(&[pair[0] as u8 | high << 4])?;
+    }
+    Ok(())
+}
+
+fn write_record<W: Write>(writer: &mut W, record: &ContextWithCount) -> io::Result<()> {
+    let ctx = &record.context;
+    write_varint(writer, record.count)?;
+    let flags = if record.block.is_some() { HAS_BLOCK } else { 0 } | if record.parent.is_some() { HAS_PARENT } else { 0 };
+    writer.write_all(&[flags])?;
+    for id in [record.block, record.parent].into_iter().flatten() {
+        write_varint(writer, id)?;
+    }
+
+    write_varint(writer, ctx.stack_size as u64)?;
+    write_varint(writer, zigzag(ctx.sp_offset as i64))?;
+    writer.write_all(&[ctx.chain_depth, ctx.self_type as u8])?;
+    write_types(writer, &ctx.local_types)?;
+    write_types(writer, &ctx.temp_types)?;
+    for &mapping in ctx.temp_mapping.iter() {
+        writer.write_all(&[encode_temp_mapping(mapping)])?;
+    }
+    Ok(())
+}
+
+pub fn write_dataset(path: &str, contexts: &[ContextWithCount]) -> io::Result<()> {
+    write_records(BufWriter::new(File::create(path)?), contexts)
+}
+
+fn write_records<W: Write>(mut writer: W, contexts: &[ContextWithCount]) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&[MAX_LOCAL_TYPES as u8, MAX_TEMP_TYPES as u8])?;
+    writer.write_all(&(contexts.len() as u64).to_le_bytes())?;
+    for record in contexts.iter() {
+        write_record(&mut writer, record)?;
+    }
+    writer.flush()
+}
+
+
+// Reads records one at a time, so a dataset never has to be in memory whole
+pub struct BinaryReader<R> {
+    reader: R,
+    remaining: u64,
+}
+
+fn read_bytes<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N], String> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => "truncated binary dataset".to_string(),
+        _ => e.to_string(),
+    })?;
+    Ok(bytes)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, String> {
+    Ok(read_bytes::<R, 1>(reader)?[0])
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> Result<u64, String> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(reader)?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("varint is too long".to_string())
+}
+
+fn decode_type(bits: u8) -> Result<Type, String> {
+    Type::ALL.get(bits as usize).copied().ok_or_else(|| format!("invalid type {}", bits))
+}
+
+fn decode_temp_mapping(byte: u8) -> TempMapping {
+    match byte {
+        0 => TempMapping::MapToStack,
+        1 => TempMapping::MapToSelf,
+        i => TempMapping::MapToLocal(i - 2),
+    }
+}
+
+fn read_types<R: Read>(reader: &mut R, types: &mut [Type]) -> Result<(), String> {
+    for pair in types.chunks_mut(2) {
+        let byte = read_u8(reader)?;
+        pair[0] = decode_type(byte & 0xf)?;
+        if let Some(high) = pair.get_mut(1) {
+            *high = decode_type(byte >> 4)?;
+        }
+    }
+    Ok(())
+}
+
+impl<R: Read> BinaryReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, String> {
+        let magic: [u8; 8] = read_bytes(&mut reader)?;
+        if &magic != MAGIC {
+            return Err("not a binary dataset".to_string());
+        }
+        let version = u16::from_le_bytes(read_bytes(&mut reader)?);
+        if version != VERSION {
+            return Err(format!("unsupported binary dataset version {}", version));
+        }
+        let [locals, temps] = read_bytes(&mut reader)?;
+        if (locals as usize, temps as usize) != (MAX_LOCAL_TYPES, MAX_TEMP_TYPES) {
+            return Err(format!(
+                "dataset has {} locals and {} temps, expected {} and {}",
+                locals, temps, MAX_LOCAL_TYPES, MAX_TEMP_TYPES
+            ));
+        }
+        let remaining = u64::from_le_bytes(read_bytes(&mut reader)?);
+        Ok(BinaryReader { reader, remaining })
+    }
+
+    fn read_record(&mut self) -> Result<ContextWithCount, String> {
+        let reader = &mut self.reader;
+        let count = read_varint(reader)?;
+        let flags = read_u8(reader)?;
+        let block = if flags & HAS_BLOCK != 0 { Some(read_varint(reader)?) } else { None };
+        let parent = if flags & HAS_PARENT != 0 { Some(read_varint(reader)?) } else { None };
+
+        let stack_size = u16::try_from(read_varint(reader)?).map_err(|_| "stack_size out of range")?;
+        let sp_offset = i16::try_from(unzigzag(read_varint(reader)?)).map_err(|_| "sp_offset out of range")?;
+        let [chain_depth, self_type] = read_bytes(reader)?;
+        let mut context = Context {
+            stack_size,
+            sp_offset,
+            chain_depth,
+            self_type: decode_type(self_type)?,
+            ..Default::default()
+        };
+        read_types(reader, &mut context.local_types)?;
+        read_types(reader, &mut context.temp_types)?;
+        for mapping in context.temp_mapping.iter_mut() {
+            *mapping = decode_temp_mapping(read_u8(reader)?);
+        }
+
+        Ok(ContextWithCount { context, count, block, parent })
+    }
+}
+
+impl<R: Read> Iterator for BinaryReader<R> {
+    type Item = Result<ContextWithCount, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(self.read_record())
+    }
+}
+
+pub fn read_dataset(path: &str) -> Result<BinaryReader<BufReader<File>>, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    BinaryReader::new(BufReader::new(file))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::{test_contexts, test_records, Generator, GeneratorConfig};
+
+    fn write(records: &[ContextWithCount]) -> Vec<u8> {
+        let mut bytes = vec![];
+        write_records(&mut bytes, records).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn seeded_records_read_back() {
+        let config = GeneratorConfig { seed: 11, contexts: 1000, successor: 0.5, ..Default::default() };
+        let mut records = Generator::new(config).dataset();
+        records.extend(test_records(&test_contexts(11, 2000)));
+        records[0].count = u64::MAX;
+
+        let bytes = write(&records);
+        let read: Vec<ContextWithCount> = BinaryReader::new(&bytes[..]).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(read.len(), records.len());
+        for (read, record) in read.iter().zip(records.iter()) {
+            assert_eq!(
+                (&read.context, read.count, read.block, read.parent),
+                (&record.context, record.count, record.block, record.parent)
+            );
+        }
+    }
+
+    #[test]
+    fn varints_and_zigzag_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut bytes = vec![];
+            write_varint(&mut bytes, value).unwrap();
+            assert_eq!(read_varint(&mut &bytes[..]), Ok(value));
+        }
+        for value in [0, 1, -1, i16::MIN as i64, i16::MAX as i64, i64::MIN, i64::MAX] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+    }
+
+    #[test]
+    fn bad_datasets_are_rejected() {
+        let bytes = write(&test_records(&test_contexts(12, 10)));
+        assert!(BinaryReader::new(&bytes[1..]).is_err());
+
+        let mut other_version = bytes.clone();
+        other_version[MAGIC.len()] += 1;
+        assert!(BinaryReader::new(&other_version[..]).is_err());
+
+        let truncated: Result<Vec<_>, _> = BinaryReader::new(&bytes[..bytes.len() - 1]).unwrap().collect();
+        assert_eq!(truncated.unwrap_err(), "truncated binary dataset");
+    }
+}
@@ -0,0 +1,259 @@
+use deepsize::DeepSizeOf;
+
+use crate::{initial_context::{Context, TempMapping, Type, MAX_LOCAL_TYPES, MAX_TEMP_TYPES}, ContextSize};
+
+// The whole Context needs 135 bits with every field at full width, so two
+// words only fit it when stack_size and sp_offset are small. Bigger values
+// escape to a side table in BitPackedStorage.
+//
+// types:  bits 0..32  local_types, 4 bits each
+//         bits 32..64 temp_types, 4 bits each
+// fields: bits 0..12  stack_size
+//         bits 12..24 sp_offset, two's complement
+//         (bits 0..24 are an index into the side table when bit 63 is set)
+//         bits 24..32 chain_depth
+//         bits 32..36 self_type
+//         bits 36..63 temp_mapping, one base 10 digit per temp
+//         bit 63      stack_size and sp_offset are in the side table
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default, DeepSizeOf)]
+pub struct BitPackedContext {
+    types: u64,
+    fields: u64,
+}
+
+const TYPE_BITS: u32 = 4;
+const TEMP_TYPES_SHIFT: u32 = TYPE_BITS * MAX_LOCAL_TYPES as u32;
+
+const STACK_SIZE_BITS: u32 = 12;
+const SP_OFFSET_SHIFT: u32 = STACK_SIZE_BITS;
+const SP_OFFSET_BITS: u32 = 12;
+const STACK_BITS: u32 = STACK_SIZE_BITS + SP_OFFSET_BITS;
+const CHAIN_DEPTH_SHIFT: u32 = STACK_BITS;
+const SELF_TYPE_SHIFT: u32 = CHAIN_DEPTH_SHIFT + 8;
+const TEMP_MAPPING_SHIFT: u32 = SELF_TYPE_SHIFT + TYPE_BITS;
+// MapToStack, MapToSelf and MapToLocal for every local
+const TEMP_MAPPING_STATES: u64 = 2 + MAX_LOCAL_TYPES as u64;
+const OVERFLOW_BIT: u64 = 1 << 63;
+
+const fn mask(bits: u32) -> u64 {
+    (1 << bits) - 1
+}
+
+const _: () = assert!(TEMP_TYPES_SHIFT + TYPE_BITS * MAX_TEMP_TYPES as u32 <= 64);
+const _: () = assert!(TEMP_MAPPING_STATES.pow(MAX_TEMP_TYPES as u32) <= mask(63 - TEMP_MAPPING_SHIFT) + 1);
+const _: () = {
+    let mut i = 0;
+    while i < Type::ALL.len() {
+        assert!(Type::ALL[i] as usize == i);
+        i += 1;
+    }
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitPackError {
+    // stack_size or sp_offset don't fit in their bit fields
+    StackOutOfRange,
+    // A temp maps to a local we don't track the type of
+    LocalIndex(u8),
+}
+
+fn encode_temp_mapping(temp_mapping: TempMapping) -> Result<u64, BitPackError> {
+    match temp_mapping {
+        TempMapping::MapToStack => Ok(0),
+        TempMapping::MapToSelf => Ok(1),
+        TempMapping::MapToLocal(i) if (i as usize) < MAX_LOCAL_TYPES => Ok(2 + i as u64),
+        TempMapping::MapToLocal(i) => Err(BitPackError::LocalIndex(i)),
+    }
+}
+
+fn decode_temp_mapping(digit: u64) -> TempMapping {
+    match digit {
+        0 => TempMapping::MapToStack,
+        1 => TempMapping::MapToSelf,
+        i => TempMapping::MapToLocal((i - 2) as u8),
+    }
+}
+
+fn decode_type(bits: u64) -> Type {
+    Type::ALL[(bits & mask(TYPE_BITS)) as usize]
+}
+
+impl BitPackedContext {
+    // Pack everything but stack_size and sp_offset, which the caller puts in
+    // the low STACK_BITS of fields
+    fn pack_without_stack(ctx: &Context) -> Result<Self, BitPackError> {
+        let mut types = 0;
+        for (i, &local_type) in ctx.local_types.iter().enumerate() {
+            types |= (local_type as u64) << (TYPE_BITS * i as u32);
+        }
+        for (i, &temp_type) in ctx.temp_types.iter().enumerate() {
+            types |= (temp_type as u64) << (TEMP_TYPES_SHIFT + TYPE_BITS * i as u32);
+        }
+
+        let mut temp_mapping = 0;
+        for &mapping in ctx.temp_mapping.iter().rev() {
+            temp_mapping = temp_mapping * TEMP_MAPPING_STATES + encode_temp_mapping(mapping)?;
+        }
+
+        let fields = (ctx.chain_depth as u64) << CHAIN_DEPTH_SHIFT
+            | (ctx.self_type as u64) << SELF_TYPE_SHIFT
+            | temp_mapping << TEMP_MAPPING_SHIFT;
+
+        Ok(BitPackedContext { types, fields })
+    }
+
+    fn stack_fits(ctx: &Context) -> bool {
+        let sp_offset_max = 1 << (SP_OFFSET_BITS - 1);
+        (ctx.stack_size as u64) <= mask(STACK_SIZE_BITS)
+            && (-sp_offset_max..sp_offset_max).contains(&(ctx.sp_offset as i32))
+    }
+
+    // Pack a context whose stack_size and sp_offset live in the side table
+    fn pack_overflow(ctx: &Context, index: usize) -> Result<Self, BitPackError> {
+        assert!(index as u64 <= mask(STACK_BITS), "side table is full");
+        let mut packed = Self::pack_without_stack(ctx)?;
+        packed.fields |= OVERFLOW_BIT | index as u64;
+        Ok(packed)
+    }
+
+    // Index into the side table, if stack_size and sp_offset aren't inline
+    pub fn overflow_index(&self) -> Option<usize> {
+        if self.fields & OVERFLOW_BIT != 0 {
+            Some((self.fields & mask(STACK_BITS)) as usize)
+        } else {
+            None
+        }
+    }
+
+    // Decode, looking stack_size and sp_offset up in the side table if needed
+    pub fn unpack(&self, overflow: &[(u16, i16)]) -> Context {
+        let mut ctx = Context::default();
+
+        for i in 0..MAX_LOCAL_TYPES {
+            ctx.local_types[i] = decode_type(self.types >> (TYPE_BITS * i as u32));
+        }
+        for i in 0..MAX_TEMP_TYPES {
+            ctx.temp_types[i] = decode_type(self.types >> (TEMP_TYPES_SHIFT + TYPE_BITS * i as u32));
+        }
+
+        (ctx.stack_size, ctx.sp_offset) = match self.overflow_index() {
+            Some(index) => overflow[index],
+            None => {
+                let stack_size = (self.fields & mask(STACK_SIZE_BITS)) as u16;
+                // Shift the sign bit of the field up to the sign bit of an i16 and back
+                let sp_offset = ((self.fields >> SP_OFFSET_SHIFT) & mask(SP_OFFSET_BITS)) as i16;
+                let sp_offset = (sp_offset << (16 - SP_OFFSET_BITS)) >> (16 - SP_OFFSET_BITS);
+                (stack_size, sp_offset)
+            }
+        };
+
+        ctx.chain_depth = (self.fields >> CHAIN_DEPTH_SHIFT) as u8;
+        ctx.self_type = decode_type(self.fields >> SELF_TYPE_SHIFT);
+
+        let mut temp_mapping = (self.fields & !OVERFLOW_BIT) >> TEMP_MAPPING_SHIFT;
+        for i in 0..MAX_TEMP_TYPES {
+            ctx.temp_mapping[i] = decode_temp_mapping(temp_mapping % TEMP_MAPPING_STATES);
+            temp_mapping /= TEMP_MAPPING_STATES;
+        }
+
+        ctx
+    }
+}
+
+// Only succeeds when stack_size and sp_offset fit inline, see BitPackedStorage
+// for the contexts that don't
+impl TryFrom<Context> for BitPackedContext {
+    type Error = BitPackError;
+
+    fn try_from(ctx: Context) -> Result<Self, BitPackError> {
+        if !Self::stack_fits(&ctx) {
+            return Err(BitPackError::StackOutOfRange);
+        }
+        let mut packed = Self::pack_without_stack(&ctx)?;
+        packed.fields |= ctx.stack_size as u64;
+        packed.fields |= (ctx.sp_offset as u64 & mask(SP_OFFSET_BITS)) << SP_OFFSET_SHIFT;
+        Ok(packed)
+    }
+}
+
+
+// Bit packed contexts plus the side table for stack sizes and offsets that
+// don't fit in their bit fields
+#[derive(Default)]
+pub struct BitPackedStorage {
+    overflow: Vec<(u16, i16)>,
+}
+
+impl ContextSize for BitPackedStorage {
+    type Context = Context;
+    type Pointer = BitPackedContext;
+    type Storage = Vec<(u16, i16)>;
+
+    fn get_storage(&self) -> Option<Self::Storage> {
+        Some(self.overflow.clone())
+    }
+
+    fn store_context(&mut self, context: Context) -> BitPackedContext {
+        match BitPackedContext::try_from(context.clone()) {
+            Ok(packed) => packed,
+            Err(BitPackError::StackOutOfRange) => {
+                // Deep stacks are rare, so a linear search keeps the table small
+                let stack = (context.stack_size, context.sp_offset);
+                let index = match self.overflow.iter().position(|&entry| entry == stack) {
+                    Some(index) => index,
+                    None => {
+                        self.overflow.push(stack);
+                        self.overflow.len() - 1
+                    }
+                };
+                BitPackedContext::pack_overflow(&context, index).expect("context can't be bit packed")
+            }
+            Err(err) => panic!("context can't be bit packed: {:?}", err),
+        }
+    }
+
+    fn load_context(&self, pointer: &BitPackedContext) -> Context {
+        pointer.unpack(&self.overflow)
+    }
+
+    fn get_analytic_storage_size(&self) -> usize {
+        self.overflow.capacity() * std::mem::size_of::<(u16, i16)>()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sp_offset_is_sign_extended() {
+        for sp_offset in [-2048, -1000, -1, 0, 1, 2047] {
+            let ctx = Context { stack_size: 4095, sp_offset, ..Default::default() };
+            let packed = BitPackedContext::try_from(ctx.clone()).unwrap();
+            assert_eq!(packed.overflow_index(), None);
+            assert_eq!(packed.unpack(&[]), ctx);
+        }
+    }
+
+    #[test]
+    fn wide_stacks_share_overflow_entries() {
+        let contexts = [
+            Context { sp_offset: 2048, ..Default::default() },
+            Context { stack_size: 4096, sp_offset: -2049, ..Default::default() },
+            Context { sp_offset: 2048, chain_depth: 3, ..Default::default() },
+        ];
+        for ctx in contexts.iter() {
+            assert_eq!(BitPackedContext::try_from(ctx.clone()), Err(BitPackError::StackOutOfRange));
+        }
+
+        let mut storage = BitPackedStorage::default();
+        let handles: Vec<BitPackedContext> = contexts.iter().map(|ctx| storage.store_context(ctx.clone())).collect();
+        let indices: Vec<Option<usize>> = handles.iter().map(BitPackedContext::overflow_index).collect();
+        assert_eq!(indices, [Some(0), Some(1), Some(0)]);
+        assert_eq!(storage.overflow, [(0, 2048), (4096, -2049)]);
+        for (ctx, handle) in contexts.iter().zip(handles.iter()) {
+            assert_eq!(storage.load_context(handle), *ctx);
+        }
+    }
+}
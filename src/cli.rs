@@ -0,0 +1,229 @@
+use std::{fmt, str::FromStr};
+
+use crate::{bench::BenchConfig, measure::BackendKind, synthetic::{self, GeneratorConfig}};
+
+const USAGE: &str = "\
+Usage: context-size [COMMAND] [OPTIONS] <DATASET>...
+       context-size generate [GENERATE OPTIONS] <OUTPUT>
+       context-size convert <INPUT> <OUTPUT>
+       context-size compare [OPTIONS] [LABEL=]<DATASET>...
+
+Commands:
+  size        Measure the total size of every selected strategy (default)
+  list        List the available strategy names
+  generate    Write a synthetic JSON lines dataset
+  convert     Rewrite a dataset as JSON lines if OUTPUT ends in .json or .jsonl, binary otherwise
+  crosscheck  Measure with every backend and flag strategies where they disagree
+  roundtrip   Check that every context survives packing and each strategy's store/load
+  compare     Size of every strategy relative to initial on each dataset and combined
+  stats       Report what the contexts in the dataset look like
+  sweep       Size of initial, compact and packed when tracking 4, 8 or 16 locals and temps
+  entropy     Compare the bits per context of every strategy with the dataset's entropy
+  bench       Time insert, decode, equality and hash per operation for every strategy
+
+Options:
+  -s, --strategy <NAME>   Only run the named strategy (repeatable, comma separated)
+  -f, --format <FORMAT>   Output format: table (default), tsv, json, csv
+  -b, --backend <NAME>    Measurement backend: deepsize (default), allocator, analytic
+      --stream            Measure sizes in one pass without loading the dataset
+                          (not with allocator, skips strategies that need training)
+  -h, --help              Print this help
+
+Bench options:
+  --warmup <N>            Untimed runs before the timed ones (default 1)
+  --runs <N>              Timed runs, the median is reported (default 5)
+
+Generate options:
+  --seed <N>              Seed for the generator (default 0)
+  --contexts <N>          Number of distinct contexts (default 10000)
+  --skew <X>              Zipf exponent of how often each context is used (default 1.0)
+  --max-count <N>         Count of the most used context (default 1000)
+  --stack-mean <X>        Mean stack_size (default 2.0)
+  --chain-depth-mean <X>  Mean chain_depth (default 0.3)
+  --local-known <P>       Chance the first local has a known type (default 0.5)
+  --temp-known <P>        Chance the first temp has a known type (default 0.7)
+  --slot-decay <X>        Factor applied to those chances for every following slot (default 0.7)
+  --types <LIST>          Weights of the known types, like Fixnum=4,CString=2
+  --temp-mapping <P>      Chance a temp maps to self or a local (default 0.3)
+  --successor <P>         Chance a context follows an earlier one, with block ids (default 0)
+";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Size,
+    List,
+    Generate,
+    Convert,
+    Crosscheck,
+    Roundtrip,
+    Compare,
+    Stats,
+    Sweep,
+    Entropy,
+    Bench,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Table,
+    Tsv,
+    Json,
+    Csv,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    pub datasets: Vec<String>,
+    // Empty means every strategy
+    pub strategies: Vec<String>,
+    pub format: Format,
+    pub backend: BackendKind,
+    pub generator: GeneratorConfig,
+    pub bench: BenchConfig,
+    // Measure sizes without loading the whole dataset
+    pub stream: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CliError {
+    Help,
+    Invalid(String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Help => write!(f, "{}", USAGE),
+            CliError::Invalid(message) => write!(f, "error: {}\n\n{}", message, USAGE),
+        }
+    }
+}
+
+fn parse_command(name: &str) -> Option<Command> {
+    match name {
+        "size" => Some(Command::Size),
+        "list" => Some(Command::List),
+        "generate" => Some(Command::Generate),
+        "convert" => Some(Command::Convert),
+        "crosscheck" => Some(Command::Crosscheck),
+        "roundtrip" => Some(Command::Roundtrip),
+        "compare" => Some(Command::Compare),
+        "stats" => Some(Command::Stats),
+        "sweep" => Some(Command::Sweep),
+        "entropy" => Some(Command::Entropy),
+        "bench" => Some(Command::Bench),
+        _ => None,
+    }
+}
+
+fn parse_number<T: FromStr>(flag: &str, value: &str) -> Result<T, CliError> {
+    value
+        .parse()
+        .map_err(|_| CliError::Invalid(format!("invalid value `{}` for `{}`", value, flag)))
+}
+
+fn parse_format(name: &str) -> Result<Format, CliError> {
+    match name {
+        "table" => Ok(Format::Table),
+        "tsv" => Ok(Format::Tsv),
+        "json" => Ok(Format::Json),
+        "csv" => Ok(Format::Csv),
+        _ => Err(CliError::Invalid(format!("unknown format `{}`", name))),
+    }
+}
+
+// Parse the arguments without the program name
+pub fn parse<I>(args: I) -> Result<Args, CliError>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter().peekable();
+
+    let command = match args.peek().and_then(|arg| parse_command(arg)) {
+        Some(command) => {
+            args.next();
+            command
+        }
+        None => Command::Size,
+    };
+
+    let mut parsed = Args {
+        command,
+        datasets: vec![],
+        strategies: vec![],
+        format: Format::Table,
+        backend: BackendKind::DeepSize,
+        generator: GeneratorConfig::default(),
+        bench: BenchConfig::default(),
+        stream: false,
+    };
+
+    while let Some(arg) = args.next() {
+        // Support both `--flag value` and `--flag=value`
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with('-') => (flag.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = |flag: &str| {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| CliError::Invalid(format!("`{}` expects a value", flag)))
+        };
+
+        match flag.as_str() {
+            "-h" | "--help" => return Err(CliError::Help),
+            "-s" | "--strategy" => {
+                let names = value(&flag)?;
+                parsed.strategies.extend(
+                    names.split(',').filter(|name| !name.is_empty()).map(str::to_string),
+                );
+            }
+            "-f" | "--format" => parsed.format = parse_format(&value(&flag)?)?,
+            "-b" | "--backend" => {
+                let name = value(&flag)?;
+                parsed.backend = BackendKind::from_name(&name)
+                    .ok_or_else(|| CliError::Invalid(format!("unknown backend `{}`", name)))?;
+            }
+            "--stream" => parsed.stream = true,
+            "--warmup" => parsed.bench.warmup = parse_number(&flag, &value(&flag)?)?,
+            "--runs" => parsed.bench.runs = parse_number(&flag, &value(&flag)?)?,
+            "--seed" => parsed.generator.seed = parse_number(&flag, &value(&flag)?)?,
+            "--contexts" => parsed.generator.contexts = parse_number(&flag, &value(&flag)?)?,
+            "--skew" => parsed.generator.skew = parse_number(&flag, &value(&flag)?)?,
+            "--max-count" => parsed.generator.max_count = parse_number(&flag, &value(&flag)?)?,
+            "--stack-mean" => parsed.generator.stack_mean = parse_number(&flag, &value(&flag)?)?,
+            "--chain-depth-mean" => parsed.generator.chain_depth_mean = parse_number(&flag, &value(&flag)?)?,
+            "--local-known" => parsed.generator.local_known = parse_number(&flag, &value(&flag)?)?,
+            "--temp-known" => parsed.generator.temp_known = parse_number(&flag, &value(&flag)?)?,
+            "--slot-decay" => parsed.generator.slot_decay = parse_number(&flag, &value(&flag)?)?,
+            "--temp-mapping" => parsed.generator.temp_mapping = parse_number(&flag, &value(&flag)?)?,
+            "--successor" => parsed.generator.successor = parse_number(&flag, &value(&flag)?)?,
+            "--types" => {
+                parsed.generator.types = synthetic::parse_type_weights(&value(&flag)?)
+                    .map_err(CliError::Invalid)?;
+            }
+            _ if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(CliError::Invalid(format!("unknown option `{}`", flag)));
+            }
+            _ => parsed.datasets.push(arg),
+        }
+    }
+
+    if parsed.command == Command::Generate && parsed.datasets.len() != 1 {
+        return Err(CliError::Invalid("generate expects a single output path".to_string()));
+    }
+    if parsed.command == Command::Convert && parsed.datasets.len() != 2 {
+        return Err(CliError::Invalid("convert expects an input and an output path".to_string()));
+    }
+    if parsed.stream && parsed.command != Command::Size {
+        return Err(CliError::Invalid("--stream only works when measuring sizes".to_string()));
+    }
+    if parsed.command != Command::List && parsed.datasets.is_empty() {
+        return Err(CliError::Invalid("no dataset given".to_string()));
+    }
+
+    Ok(parsed)
+}
+
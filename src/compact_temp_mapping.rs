@@ -1,9 +1,11 @@
-use deepsize::DeepSizeOf;
+use deepsize::{Context as DeepSizeContext, DeepSizeOf};
 use serde::{Serialize, Deserialize};
 
-use crate::{initial_context::{self, Type, MAX_TEMP_TYPES, MAX_LOCAL_TYPES}, ContextSize};
+use crate::{initial_context::{self, serde_array, Type, MAX_TEMP_TYPES, MAX_LOCAL_TYPES}, ContextSize};
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, Serialize, Deserialize, DeepSizeOf)]
+// Enough indices to sweep up to 16 tracked locals while TempMapping still
+// fits in a byte
+#[derive(Copy, Clone, Eq, PartialEq, PartialOrd, Ord, Debug, Hash, Serialize, Deserialize, DeepSizeOf)]
 pub enum LocalIndex {
     Local0,
     Local1,
@@ -13,22 +15,81 @@ pub enum LocalIndex {
     Local5,
     Local6,
     Local7,
+    Local8,
+    Local9,
+    Local10,
+    Local11,
+    Local12,
+    Local13,
+    Local14,
+    Local15,
+}
+
+impl LocalIndex {
+    // Every variant, in declaration order
+    pub const ALL: [LocalIndex; 16] = [
+        LocalIndex::Local0,
+        LocalIndex::Local1,
+        LocalIndex::Local2,
+        LocalIndex::Local3,
+        LocalIndex::Local4,
+        LocalIndex::Local5,
+        LocalIndex::Local6,
+        LocalIndex::Local7,
+        LocalIndex::Local8,
+        LocalIndex::Local9,
+        LocalIndex::Local10,
+        LocalIndex::Local11,
+        LocalIndex::Local12,
+        LocalIndex::Local13,
+        LocalIndex::Local14,
+        LocalIndex::Local15,
+    ];
+}
+
+const _: () = assert!(std::mem::size_of::<TempMapping>() == 1);
+
+impl TryFrom<u8> for LocalIndex {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        LocalIndex::ALL.get(value as usize).copied().ok_or(value)
+    }
 }
 
 
 // Potential mapping of a value on the temporary stack to
 // self, a local variable or constant so that we can track its type
-#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize, DeepSizeOf)]
+#[derive(Copy, Clone, Eq, PartialEq, PartialOrd, Ord, Hash, Debug, Default, Serialize, Deserialize, DeepSizeOf)]
 #[allow(clippy::enum_variant_names)]
 pub enum TempMapping {
+    #[default]
     MapToStack,     // Normal stack value
     MapToSelf,      // Temp maps to the self operand
     MapToLocal(LocalIndex), // Temp maps to a local variable with index
 }
 
-impl Default for TempMapping {
-    fn default() -> Self {
-        TempMapping::MapToStack
+// Fails with the local index when it's outside of the locals we track
+impl TryFrom<initial_context::TempMapping> for TempMapping {
+    type Error = u8;
+
+    fn try_from(value: initial_context::TempMapping) -> Result<Self, u8> {
+        match value {
+            initial_context::TempMapping::MapToStack => Ok(TempMapping::MapToStack),
+            initial_context::TempMapping::MapToSelf => Ok(TempMapping::MapToSelf),
+            initial_context::TempMapping::MapToLocal(local_index) => {
+                Ok(TempMapping::MapToLocal(local_index.try_into()?))
+            }
+        }
+    }
+}
+
+// Like the TryFrom, but also fails for locals past the LOCALS we track
+pub fn tracked_mapping<const LOCALS: usize>(value: initial_context::TempMapping) -> Result<TempMapping, u8> {
+    const { assert!(LOCALS <= LocalIndex::ALL.len()) };
+    match value {
+        initial_context::TempMapping::MapToLocal(local_index) if local_index as usize >= LOCALS => Err(local_index),
+        mapping => mapping.try_into(),
     }
 }
 
@@ -36,8 +97,8 @@ impl Default for TempMapping {
 /// Code generation context
 /// Contains information we can use to specialize/optimize code
 /// There are a lot of context objects so we try to keep the size small.
-#[derive(Clone, Default, PartialEq, Eq, Hash, Debug, Serialize, Deserialize, DeepSizeOf)]
-pub struct Context {
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct Context<const LOCALS: usize = MAX_LOCAL_TYPES, const TEMPS: usize = MAX_TEMP_TYPES> {
     // Number of values currently on the temporary stack
     pub stack_size: u16,
 
@@ -49,23 +110,51 @@ pub struct Context {
     pub chain_depth: u8,
 
     // Local variable types we keep track of
-    pub local_types: [Type; MAX_LOCAL_TYPES],
+    #[serde(with = "serde_array")]
+    pub local_types: [Type; LOCALS],
 
     // Temporary variable types we keep track of
-    pub temp_types: [Type; MAX_TEMP_TYPES],
+    #[serde(with = "serde_array")]
+    pub temp_types: [Type; TEMPS],
 
     // Type we track for self
     pub self_type: Type,
 
     // Mapping of temp stack entries to types we track
-    pub temp_mapping: [TempMapping; MAX_TEMP_TYPES],
+    #[serde(with = "serde_array")]
+    pub temp_mapping: [TempMapping; TEMPS],
+}
+
+impl<const LOCALS: usize, const TEMPS: usize> Default for Context<LOCALS, TEMPS> {
+    fn default() -> Self {
+        Context {
+            stack_size: 0,
+            sp_offset: 0,
+            chain_depth: 0,
+            local_types: [Type::Unknown; LOCALS],
+            temp_types: [Type::Unknown; TEMPS],
+            self_type: Type::Unknown,
+            temp_mapping: [TempMapping::MapToStack; TEMPS],
+        }
+    }
 }
 
+// Every field is stored inline
+impl<const LOCALS: usize, const TEMPS: usize> DeepSizeOf for Context<LOCALS, TEMPS> {
+    fn deep_size_of_children(&self, _context: &mut DeepSizeContext) -> usize {
+        0
+    }
+}
 
 
-impl From<initial_context::Context> for Context {
-    fn from(value: initial_context::Context) -> Self {
-        let mut context = Context::default();
+impl<const LOCALS: usize, const TEMPS: usize> From<initial_context::Context<LOCALS, TEMPS>> for Context<LOCALS, TEMPS> {
+    fn from(value: initial_context::Context<LOCALS, TEMPS>) -> Self {
+        let mut context = Context {
+            stack_size: value.stack_size,
+            sp_offset: value.sp_offset,
+            chain_depth: value.chain_depth,
+            ..Context::default()
+        };
 
         // Copy local types
         for (i, local_type) in value.local_types.iter().enumerate() {
@@ -82,28 +171,33 @@ impl From<initial_context::Context> for Context {
 
         // Copy temp mapping
         for (i, temp_mapping) in value.temp_mapping.iter().enumerate() {
-            match temp_mapping {
-                initial_context::TempMapping::MapToStack => {
-                    context.temp_mapping[i] = TempMapping::MapToStack;
-                }
-                initial_context::TempMapping::MapToSelf => {
-                    context.temp_mapping[i] = TempMapping::MapToSelf;
-                }
-                initial_context::TempMapping::MapToLocal(local_index) => {
-                    let local_index = match local_index {
-                        0 => LocalIndex::Local0,
-                        1 => LocalIndex::Local1,
-                        2 => LocalIndex::Local2,
-                        3 => LocalIndex::Local3,
-                        4 => LocalIndex::Local4,
-                        5 => LocalIndex::Local5,
-                        6 => LocalIndex::Local6,
-                        7 => LocalIndex::Local7,
-                        _ => unreachable!(),
-                    };
-                    context.temp_mapping[i] = TempMapping::MapToLocal(local_index);
+            context.temp_mapping[i] = tracked_mapping::<LOCALS>(*temp_mapping).unwrap();
+        }
+
+        context
+    }
+}
+
+impl<const LOCALS: usize, const TEMPS: usize> From<Context<LOCALS, TEMPS>> for initial_context::Context<LOCALS, TEMPS> {
+    fn from(value: Context<LOCALS, TEMPS>) -> Self {
+        let mut context = initial_context::Context {
+            stack_size: value.stack_size,
+            sp_offset: value.sp_offset,
+            chain_depth: value.chain_depth,
+            local_types: value.local_types,
+            temp_types: value.temp_types,
+            self_type: value.self_type,
+            ..initial_context::Context::default()
+        };
+
+        for (i, temp_mapping) in value.temp_mapping.iter().enumerate() {
+            context.temp_mapping[i] = match temp_mapping {
+                TempMapping::MapToStack => initial_context::TempMapping::MapToStack,
+                TempMapping::MapToSelf => initial_context::TempMapping::MapToSelf,
+                TempMapping::MapToLocal(local_index) => {
+                    initial_context::TempMapping::MapToLocal(*local_index as u8)
                 }
-            }
+            };
         }
 
         context
@@ -115,11 +209,45 @@ impl ContextSize for Context {
     type Context = Context;
     type Pointer = Context;
     type Storage = ();
-    fn get_pointer(&self) -> Context {
-        self.clone()
-    }
 
     fn get_storage(&self) -> Option<()> {
         None
     }
+
+    fn store_context(&mut self, context: Context) -> Context {
+        context
+    }
+
+    fn load_context(&self, pointer: &Context) -> initial_context::Context {
+        pointer.clone().into()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::{assert_equal_handles, test_contexts};
+
+    #[test]
+    fn converts_back_to_the_same_context() {
+        let contexts = test_contexts(1, 1000);
+        let compact: Vec<Context> = contexts.iter().cloned().map(Context::from).collect();
+        for (ctx, compact) in contexts.iter().zip(compact.iter()) {
+            assert_eq!(initial_context::Context::from(compact.clone()), *ctx);
+        }
+        assert_equal_handles(&contexts, &compact);
+    }
+
+    #[test]
+    fn untracked_locals_are_rejected() {
+        for index in 0..=u8::MAX {
+            let mapping = tracked_mapping::<MAX_LOCAL_TYPES>(initial_context::TempMapping::MapToLocal(index));
+            if (index as usize) < MAX_LOCAL_TYPES {
+                assert_eq!(mapping, Ok(TempMapping::MapToLocal(index.try_into().unwrap())));
+            } else {
+                assert_eq!(mapping, Err(index));
+            }
+        }
+    }
 }
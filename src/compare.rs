@@ -0,0 +1,116 @@
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::{cli::Format, report::csv_field, Strategy};
+
+// Label of the column that sums up every dataset
+pub const COMBINED: &str = "combined";
+
+// One strategy on one dataset, or on all of them for the combined column
+#[derive(Clone, Debug, Serialize)]
+pub struct CompareCell {
+    pub strategy: &'static str,
+    pub dataset: String,
+    pub bytes: usize,
+    // bytes over what initial_context::Context takes. For the combined column
+    // this is the geometric mean over the datasets, so every benchmark counts
+    // the same however many contexts it has. None when there's nothing to
+    // compare, like an empty dataset, and such datasets are left out of the mean.
+    pub ratio: Option<f64>,
+}
+
+// Datasets are given as LABEL=PATH, or just PATH to label them by file name
+pub fn split_label(arg: &str) -> (String, String) {
+    match arg.split_once('=') {
+        Some((label, path)) if !label.is_empty() => (label.to_string(), path.to_string()),
+        _ => {
+            let label = Path::new(arg).file_stem().map_or(arg.into(), |stem| stem.to_string_lossy());
+            (label.to_string(), arg.to_string())
+        }
+    }
+}
+
+// sizes[i][j] is the size of strategies[i] on the dataset labels[j], and
+// baseline[j] the size of initial_context::Context on it
+pub fn comparison(labels: &[String], baseline: &[usize], strategies: &[Strategy], sizes: &[Vec<usize>]) -> Vec<CompareCell> {
+    let mut cells = vec![];
+    for (strategy, sizes) in strategies.iter().zip(sizes.iter()) {
+        let (mut log_ratios, mut ratios) = (0.0, 0);
+        for ((label, &bytes), &base) in labels.iter().zip(sizes.iter()).zip(baseline.iter()) {
+            let ratio = if bytes == 0 || base == 0 { None } else { Some(bytes as f64 / base as f64) };
+            if let Some(ratio) = ratio {
+                log_ratios += ratio.ln();
+                ratios += 1;
+            }
+            cells.push(CompareCell { strategy: strategy.name, dataset: label.clone(), bytes, ratio });
+        }
+        cells.push(CompareCell {
+            strategy: strategy.name,
+            dataset: COMBINED.to_string(),
+            bytes: sizes.iter().sum(),
+            ratio: if ratios == 0 { None } else { Some((log_ratios / ratios as f64).exp()) },
+        });
+    }
+    cells
+}
+
+// The ratio for TSV and CSV, empty when there isn't one
+fn ratio_field(cell: &CompareCell) -> String {
+    cell.ratio.map_or(String::new(), |ratio| format!("{:.4}", ratio))
+}
+
+pub fn print_comparison(format: Format, labels: &[String], cells: &[CompareCell]) {
+    match format {
+        Format::Table => {
+            println!("ratio to initial_context::Context (bytes), combined is the geometric mean");
+            let columns = labels.len() + 1;
+            print!("{0: <16}", "strategy");
+            for label in labels.iter().map(String::as_str).chain(std::iter::once(COMBINED)) {
+                print!(" {0: >24}", label);
+            }
+            println!();
+            for row in cells.chunks(columns) {
+                print!("{0: <16}", row[0].strategy);
+                for cell in row.iter() {
+                    let ratio = cell.ratio.map_or("-".to_string(), |ratio| format!("{:.3}", ratio));
+                    print!(" {0: >24}", format!("{} ({})", ratio, cell.bytes));
+                }
+                println!();
+            }
+        }
+        Format::Tsv => {
+            for cell in cells.iter() {
+                println!("{}\t{}\t{}\t{}", cell.strategy, cell.dataset, cell.bytes, ratio_field(cell));
+            }
+        }
+        Format::Json => {
+            println!("{}", serde_json::to_string_pretty(cells).unwrap());
+        }
+        Format::Csv => {
+            println!("strategy,dataset,bytes,ratio");
+            for cell in cells.iter() {
+                println!("{},{},{},{}", csv_field(cell.strategy), csv_field(&cell.dataset), cell.bytes, ratio_field(cell));
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_datasets_are_left_out_of_the_mean() {
+        let strategies: Vec<Strategy> = crate::strategies().into_iter().take(1).collect();
+        let labels = ["a".to_string(), "empty".to_string(), "b".to_string()];
+        let cells = comparison(&labels, &[100, 0, 100], &strategies, &[vec![50, 0, 200]]);
+        let ratios: Vec<Option<f64>> = cells.iter().map(|cell| cell.ratio).collect();
+        assert_eq!(ratios, [Some(0.5), None, Some(2.0), Some(1.0)]);
+        assert_eq!(cells[3].bytes, 250);
+
+        let cells = comparison(&labels[1..2], &[0], &strategies, &[vec![0]]);
+        assert_eq!(cells[1].ratio, None);
+    }
+}
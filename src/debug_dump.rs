@@ -0,0 +1,194 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use debug_parser::{Value, ValueKind};
+
+use crate::initial_context::{Context, ContextWithCount};
+
+// Marks the start of every context in a dump made with `{:?}` or `{:#?}`
+const CONTEXT_START: &str = "Context {";
+
+// How much of a file is_debug_dump needs to see, enough to get past the
+// lines a log starts with before the first context
+pub const PREFIX_LEN: usize = 64 * 1024;
+
+// Does the start of a file look like a YJIT log rather than a JSON lines dump.
+// Empty files aren't, so an empty dataset loads as one with no records.
+pub fn is_debug_dump(prefix: &[u8]) -> bool {
+    prefix.windows(CONTEXT_START.len()).any(|window| window == CONTEXT_START.as_bytes())
+}
+
+// Parse every Debug formatted Context in a YJIT log, in the order each one is
+// first seen, with how many times it appears. Anything between the contexts
+// (log prefixes, other output) is ignored.
+pub fn parse_dump(text: &str) -> Result<Vec<ContextWithCount>, String> {
+    let mut contexts: Vec<ContextWithCount> = vec![];
+    let mut indices: HashMap<Context, usize> = HashMap::new();
+
+    let found = find_contexts(text)?;
+    if found.is_empty() {
+        return Err(format!("no `{}` found, not a YJIT log or a JSON lines dataset", CONTEXT_START));
+    }
+
+    for (line, source) in found {
+        let context = parse_context(source).map_err(|e| format!("line {}: {}", line, e))?;
+        match indices.entry(context) {
+            Entry::Occupied(entry) => contexts[*entry.get()].count += 1,
+            Entry::Vacant(entry) => {
+                contexts.push(ContextWithCount {
+                    context: entry.key().clone(),
+                    count: 1,
+                    block: None,
+                    parent: None,
+                });
+                entry.insert(contexts.len() - 1);
+            }
+        }
+    }
+
+    Ok(contexts)
+}
+
+// Find the source of each `Context { ... }` with the line it starts on
+fn find_contexts(text: &str) -> Result<Vec<(usize, &str)>, String> {
+    let mut found = vec![];
+    let mut offset = 0;
+    // Line of `counted`, counting only the text since the last match so logs
+    // of hundreds of MB are scanned once
+    let (mut line, mut counted) = (1, 0);
+    while let Some(start) = text[offset..].find(CONTEXT_START).map(|i| i + offset) {
+        line += text[counted..start].matches('\n').count();
+        counted = start;
+
+        // Skip over names that only end in Context, like PackedContext
+        let preceded_by_ident = text[..start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_');
+        if preceded_by_ident {
+            offset = start + CONTEXT_START.len();
+            continue;
+        }
+
+        let mut depth = 0;
+        let mut end = None;
+        for (i, c) in text[start..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = Some(start + i + 1);
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let end = end.ok_or_else(|| format!("line {}: unterminated context", line))?;
+        found.push((line, &text[start..end]));
+        offset = end;
+    }
+    Ok(found)
+}
+
+fn parse_context(source: &str) -> Result<Context, String> {
+    let json = to_json(&debug_parser::parse(source))?;
+    serde_json::from_value(json).map_err(|e| e.to_string())
+}
+
+// The derived Debug output has the same structure as the serde representation
+// of the same type, so convert to JSON and let the Deserialize impls do the rest
+fn to_json(value: &Value) -> Result<serde_json::Value, String> {
+    match (&value.name, &value.kind) {
+        // Struct, the name is the type name
+        (_, ValueKind::Map(map)) => map
+            .values
+            .iter()
+            .map(|field| Ok((field.key.clone(), to_json(&field.value)?)))
+            .collect::<Result<serde_json::Map<_, _>, String>>()
+            .map(serde_json::Value::Object),
+        // Unit variant
+        (Some(name), ValueKind::Term(term)) if term.is_empty() => Ok(serde_json::Value::String(name.clone())),
+        // Tuple variant
+        (Some(name), ValueKind::Tuple(tuple)) => {
+            let fields = tuple.values.iter().map(to_json).collect::<Result<Vec<_>, _>>()?;
+            let fields = match <[_; 1]>::try_from(fields) {
+                Ok([field]) => field,
+                Err(fields) => serde_json::Value::Array(fields),
+            };
+            Ok(serde_json::json!({ name.clone(): fields }))
+        }
+        (None, ValueKind::List(list)) => list
+            .values
+            .iter()
+            .map(to_json)
+            .collect::<Result<Vec<_>, _>>()
+            .map(serde_json::Value::Array),
+        (None, ValueKind::Term(term)) => {
+            serde_json::from_str(term).map_err(|_| format!("unexpected value `{}`", term))
+        }
+        _ => Err(format!("unexpected value `{}`", value)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initial_context::{TempMapping, Type};
+
+    fn logged_context() -> Context {
+        let mut context: Context = Context { stack_size: 3, sp_offset: -2, chain_depth: 1, self_type: Type::UnknownHeap, ..Default::default() };
+        context.local_types[0] = Type::Fixnum;
+        context.temp_types[2] = Type::CString;
+        context.temp_mapping[0] = TempMapping::MapToLocal(5);
+        context.temp_mapping[1] = TempMapping::MapToSelf;
+        context
+    }
+
+    #[test]
+    fn parses_both_debug_formats() {
+        let context = logged_context();
+        let text = format!(
+            "yjit: compiling block ctx={:?}\nPackedContext {{ bytes: [1, 2] }}\n{:#?}\n{:?}\n",
+            context,
+            context,
+            <Context>::default()
+        );
+        let contexts = parse_dump(&text).unwrap();
+        assert_eq!(contexts.len(), 2);
+        assert_eq!((&contexts[0].context, contexts[0].count), (&context, 2));
+        assert_eq!((&contexts[1].context, contexts[1].count), (&<Context>::default(), 1));
+    }
+
+    #[test]
+    fn logs_are_told_apart_from_json_lines() {
+        let log = format!("yjit: compiling block ctx={:?}\n", logged_context());
+        assert!(is_debug_dump(log.as_bytes()));
+        assert!(is_debug_dump(format!("{:#?}", logged_context()).as_bytes()));
+
+        let json = serde_json::json!({ "context": logged_context(), "count": 1 }).to_string();
+        assert!(!is_debug_dump(json.as_bytes()));
+        assert!(!is_debug_dump(b""));
+        assert!(!is_debug_dump(b" \n\n"));
+    }
+
+    #[test]
+    fn text_without_contexts_is_an_error() {
+        assert!(parse_dump("not a log\n").is_err());
+        assert!(parse_dump("").is_err());
+    }
+
+    #[test]
+    fn bad_contexts_say_where_they_are() {
+        let text = format!("{:?}\nContext {{ stack_size: x }}\n", <Context>::default());
+        assert!(parse_dump(&text).unwrap_err().starts_with("line 2:"));
+
+        // Lines are counted across several contexts, some of them over many lines
+        let pretty = format!("{:#?}", logged_context());
+        let text = format!("{}\n{:?} {}\nContext {{ stack_size: x }}\n", pretty, logged_context(), pretty);
+        let line = 2 * pretty.lines().count() + 1;
+        assert!(parse_dump(&text).unwrap_err().starts_with(&format!("line {}:", line)));
+        assert!(parse_dump("Context { stack_size: 1,").unwrap_err().contains("unterminated"));
+    }
+}
@@ -1,8 +1,8 @@
-use std::{collections::HashSet, rc::Rc, mem::size_of};
+use std::{borrow::Borrow, collections::HashSet, hash::{Hash, Hasher}, mem::size_of, ptr, rc::Rc};
 
 use deepsize::DeepSizeOf;
 
-use crate::{ContextSize};
+use crate::{initial_context, measure::{hash_table_size, rc_box_size, rclite_inner_size}, ContextSize};
 
 
 pub struct ContextStorage<Context>  {
@@ -22,34 +22,51 @@ impl<Context> ContextStorage<Context> where Context : DeepSizeOf + Eq + std::has
         }
     }
 
-    fn insert<T : Into<Context>>(&mut self, context: T) {
-        self.hash_set.insert(context.into());
+    // Returns the shared copy of the context, adding it if it isn't stored yet
+    fn insert<T : Into<Context>>(&mut self, context: T) -> Context {
+        let context = context.into();
+        if let Some(existing) = self.hash_set.get(&context) {
+            return existing.clone();
+        }
+        self.hash_set.insert(context.clone());
+        context
     }
 
 }
 
 
-impl<Context> ContextSize for ContextStorage<Rc<Context>> where Context : DeepSizeOf + Eq + std::hash::Hash + Clone + Default {
+impl<Context> ContextSize for ContextStorage<Rc<Context>> where Context : DeepSizeOf + Eq + std::hash::Hash + Clone + Default + Into<initial_context::Context> + ContextSize<Pointer = Context> {
     type Context = Context;
     type Pointer = Rc<Context>;
     type Storage = HashSet<Rc<Context>>;
 
-    fn get_pointer(&self) -> Rc<Context> {
-        self.hash_set.iter().next().unwrap().clone()
-    }
-
     fn get_storage(&self) -> Option<HashSet<Rc<Context>>> {
         Some(self.hash_set.clone())
     }
 
-    fn get_pointer_size(&self, count: usize) -> usize {
+    fn get_pointer_size(&self, _pointer: &Self::Pointer, count: usize) -> usize {
         // Don't do deepsize here because we are just referencing
         let pointer_size = size_of::<Self::Pointer>();
         pointer_size * count
     }
 
-    fn store_context(&mut self, context: Self::Context) {
-        self.insert(context);
+    fn store_context(&mut self, context: Self::Context) -> Self::Pointer {
+        self.insert(context)
+    }
+
+    fn load_context(&self, pointer: &Self::Pointer) -> initial_context::Context {
+        (**pointer).clone().into()
+    }
+
+    fn holds(context: &initial_context::Context) -> bool {
+        Context::holds(context)
+    }
+
+    fn get_analytic_storage_size(&self) -> usize {
+        let entries: usize = self.hash_set.iter().map(|context| {
+            rc_box_size::<Context>() - size_of::<Context>() + Context::default().get_analytic_pointer_size(context)
+        }).sum();
+        hash_table_size::<Rc<Context>>(self.hash_set.capacity()) + entries
     }
 }
 
@@ -76,75 +93,255 @@ impl<Context> Default for ContextStorage<RcLite<Context>> where Context : DeepSi
 
 
 
-impl<Context> ContextSize for ContextStorage<RcLite<Context>> where Context : DeepSizeOf + Eq + std::hash::Hash + Clone + Default {
+impl<Context> ContextSize for ContextStorage<RcLite<Context>> where Context : DeepSizeOf + Eq + std::hash::Hash + Clone + Default + Into<initial_context::Context> + ContextSize<Pointer = Context> {
     type Context = Context;
     type Pointer = RcLite<Context>;
     type Storage = HashSet<RcLite<Context>>;
 
-    fn get_pointer(&self) -> RcLite<Context> {
-        self.hash_set.iter().next().unwrap().clone()
-    }
-
     fn get_storage(&self) -> Option<HashSet<RcLite<Context>>> {
         Some(self.hash_set.clone())
     }
 
-    fn get_pointer_size(&self, count: usize) -> usize {
+    fn get_pointer_size(&self, _pointer: &Self::Pointer, count: usize) -> usize {
         // Don't do deepsize here because we are just referencing
         let pointer_size = size_of::<Self::Pointer>();
         pointer_size * count
     }
 
-    fn store_context(&mut self, context: Self::Context) {
-        self.insert(context);
+    fn store_context(&mut self, context: Self::Context) -> Self::Pointer {
+        self.insert(context)
+    }
+
+    fn load_context(&self, pointer: &Self::Pointer) -> initial_context::Context {
+        (*pointer.0).clone().into()
+    }
+
+    fn holds(context: &initial_context::Context) -> bool {
+        Context::holds(context)
+    }
+
+    fn get_analytic_storage_size(&self) -> usize {
+        let entries: usize = self.hash_set.iter().map(|context| {
+            rclite_inner_size::<Context>() - size_of::<Context>() + Context::default().get_analytic_pointer_size(&context.0)
+        }).sum();
+        hash_table_size::<RcLite<Context>>(self.hash_set.capacity()) + entries
     }
 }
 
 
-#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+// Points into a ChunkedArena. Handles compare and hash by address, which the
+// storage's dedup makes the same as comparing the contexts, so they never have
+// to be dereferenced. Only the storage the handle came from can load it.
+#[derive(Debug)]
 pub struct RawPointer<T>(*const T);
 
-impl<T> DeepSizeOf for RawPointer<T> where T : DeepSizeOf {
-    // We aren't going to take the deepsize of the pointer, so it is fine to have a dummy implementation
+impl<T> Clone for RawPointer<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for RawPointer<T> {}
+
+impl<T> PartialEq for RawPointer<T> {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self.0, other.0)
+    }
+}
+
+impl<T> Eq for RawPointer<T> {}
+
+impl<T> Hash for RawPointer<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        ptr::hash(self.0, state)
+    }
+}
+
+impl<T> DeepSizeOf for RawPointer<T> {
+    // The context belongs to the arena, which is counted on its own
+    fn deep_size_of_children(&self, _context: &mut deepsize::Context) -> usize {
+        0
+    }
+}
+
+
+// Key of the storage's hash set, which compares and hashes as the context it
+// points at so a new context can be looked up by value. Keys never leave the
+// storage that owns the arena they point into.
+struct ArenaKey<T>(*const T);
+
+impl<T> ArenaKey<T> {
+    fn get(&self) -> &T {
+        // The arena lives as long as the storage and never moves or frees a context
+        unsafe { &*self.0 }
+    }
+}
+
+impl<T: PartialEq> PartialEq for ArenaKey<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl<T: Eq> Eq for ArenaKey<T> {}
+
+impl<T: Hash> Hash for ArenaKey<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.get().hash(state)
+    }
+}
+
+impl<T> Borrow<T> for ArenaKey<T> {
+    fn borrow(&self) -> &T {
+        self.get()
+    }
+}
+
+impl<T> DeepSizeOf for ArenaKey<T> {
     fn deep_size_of_children(&self, _context: &mut deepsize::Context) -> usize {
         0
     }
 }
 
 
-impl<Context> Default for ContextStorage<RawPointer<Context>> where Context : DeepSizeOf + Eq + std::hash::Hash + Clone + Default {
+const CHUNK_LEN: usize = 256;
+
+// Contexts in fixed size chunks that are never reallocated, so a context stays
+// at the same address for as long as the arena is alive
+#[derive(DeepSizeOf)]
+pub struct ChunkedArena<T> {
+    chunks: Vec<Vec<T>>,
+}
+
+impl<T> Default for ChunkedArena<T> {
     fn default() -> Self {
-        Self::new()
+        ChunkedArena { chunks: vec![] }
     }
 }
 
-impl<T> From<T> for RawPointer<T> {
-    fn from(t: T) -> Self {
-        Self(&t as *const T)
+impl<T> ChunkedArena<T> {
+    pub fn alloc(&mut self, value: T) -> RawPointer<T> {
+        if self.chunks.last().is_none_or(|chunk| chunk.len() == chunk.capacity()) {
+            self.chunks.push(Vec::with_capacity(CHUNK_LEN));
+        }
+        let chunk = self.chunks.last_mut().unwrap();
+        chunk.push(value);
+        RawPointer(chunk.last().unwrap())
+    }
+
+    // The value a handle from this arena points at. Panics for a handle from
+    // another arena rather than reading memory this one doesn't own.
+    pub fn get(&self, pointer: RawPointer<T>) -> &T {
+        let chunk = self
+            .chunks
+            .iter()
+            .find(|chunk| chunk.as_ptr_range().contains(&pointer.0))
+            .expect("handle from another arena");
+        &chunk[(pointer.0 as usize - chunk.as_ptr() as usize) / size_of::<T>()]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.chunks.iter().flatten()
+    }
+
+    // Bytes of the chunk list and the chunks, not counting what the values own
+    pub fn get_analytic_size(&self) -> usize {
+        self.chunks.capacity() * size_of::<Vec<T>>()
+            + self.chunks.iter().map(|chunk| chunk.capacity() * size_of::<T>()).sum::<usize>()
     }
 }
 
 
-impl<Context> ContextSize for ContextStorage<RawPointer<Context>> where Context : DeepSizeOf + Eq + std::hash::Hash + Clone + Default {
+// Unique contexts in a ChunkedArena, with raw pointers into it as handles
+pub struct RawPointerStorage<Context> {
+    arena: ChunkedArena<Context>,
+    hash_set: HashSet<ArenaKey<Context>>,
+}
+
+impl<Context> Default for RawPointerStorage<Context> {
+    fn default() -> Self {
+        RawPointerStorage {
+            arena: ChunkedArena::default(),
+            hash_set: HashSet::new(),
+        }
+    }
+}
+
+impl<Context> ContextSize for RawPointerStorage<Context> where Context : DeepSizeOf + Eq + std::hash::Hash + Clone + Default + Into<initial_context::Context> + ContextSize<Pointer = Context> {
     type Context = Context;
     type Pointer = RawPointer<Context>;
-    type Storage = HashSet<RawPointer<Context>>;
+    type Storage = ChunkedArena<Context>;
 
-    fn get_pointer(&self) -> RawPointer<Context> {
-        self.hash_set.iter().next().unwrap().clone()
+    // The pointers can't outlive the arena, so there's nothing to hand out
+    fn get_storage(&self) -> Option<ChunkedArena<Context>> {
+        None
     }
 
-    fn get_storage(&self) -> Option<HashSet<RawPointer<Context>>> {
-        Some(self.hash_set.clone())
+    fn get_storage_size(&self) -> usize {
+        self.arena.deep_size_of() + self.hash_set.deep_size_of()
     }
 
-    fn get_pointer_size(&self, count: usize) -> usize {
-        // Don't do deepsize here because we are just referencing
-        let pointer_size = size_of::<Self::Pointer>();
-        pointer_size * count
+    fn store_context(&mut self, context: Self::Context) -> Self::Pointer {
+        if let Some(existing) = self.hash_set.get(&context) {
+            return RawPointer(existing.0);
+        }
+        let pointer = self.arena.alloc(context);
+        self.hash_set.insert(ArenaKey(pointer.0));
+        pointer
+    }
+
+    fn load_context(&self, pointer: &Self::Pointer) -> initial_context::Context {
+        self.arena.get(*pointer).clone().into()
+    }
+
+    fn holds(context: &initial_context::Context) -> bool {
+        Context::holds(context)
+    }
+
+    fn get_analytic_storage_size(&self) -> usize {
+        let heap: usize = self.arena.iter().map(|context| {
+            Context::default().get_analytic_pointer_size(context) - size_of::<Context>()
+        }).sum();
+        hash_table_size::<ArenaKey<Context>>(self.hash_set.capacity()) + self.arena.get_analytic_size() + heap
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compact_temp_mapping, synthetic::test_contexts};
+
+    #[test]
+    fn chunks_fill_before_the_next_one() {
+        let mut storage: RawPointerStorage<compact_temp_mapping::Context> = RawPointerStorage::default();
+        for ctx in test_contexts(8, 3000) {
+            storage.store_context(ctx.into());
+        }
+        let unique = storage.hash_set.len();
+        assert!(unique > 2 * CHUNK_LEN);
+        assert_eq!(storage.arena.chunks.len(), unique.div_ceil(CHUNK_LEN));
+        assert!(storage.arena.chunks.iter().all(|chunk| chunk.capacity() == CHUNK_LEN));
+    }
+
+    #[test]
+    fn chunks_never_move() {
+        let mut arena = ChunkedArena::default();
+        let pointers: Vec<RawPointer<usize>> = (0..CHUNK_LEN * 3 + 1).map(|value| arena.alloc(value)).collect();
+        assert_eq!(arena.chunks.len(), 4);
+        for (value, pointer) in pointers.iter().enumerate() {
+            assert_eq!(*arena.get(*pointer), value);
+        }
+        assert!(arena.iter().copied().eq(0..CHUNK_LEN * 3 + 1));
     }
 
-    fn store_context(&mut self, context: Self::Context) {
-        self.insert(context);
+    #[test]
+    fn handles_only_load_from_their_arena() {
+        let mut first = ChunkedArena::default();
+        let mut second = ChunkedArena::default();
+        let pointer = first.alloc(1u64);
+        second.alloc(1u64);
+        assert_eq!(*first.get(pointer), 1);
+        assert!(std::panic::catch_unwind(|| *second.get(pointer)).is_err());
     }
 }
@@ -0,0 +1,229 @@
+use std::{collections::HashMap, hash::Hash};
+
+use serde::Serialize;
+
+use crate::{
+    cli::Format,
+    initial_context::{Context, ContextWithCount, MAX_LOCAL_TYPES, MAX_TEMP_TYPES},
+    packed_context::{pack_context, ContextDelta},
+    report::{csv_field, SizeResult},
+};
+
+// Orders of the n-gram models over the delta sequences
+const NGRAM_ORDERS: [usize; 4] = [0, 1, 2, 3];
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Entropy {
+    pub name: String,
+    // Bits per context occurrence
+    pub bits: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StrategyBits {
+    pub strategy: &'static str,
+    pub bits: f64,
+    // How many times the joint entropy the strategy spends, None when the
+    // dataset has a single unique context and the joint entropy is 0
+    pub over_bound: Option<f64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EntropyReport {
+    pub dataset: String,
+    pub total_contexts: u64,
+    pub fields: Vec<Entropy>,
+    // What coding every field on its own would need
+    pub field_sum: f64,
+    // Entropy of the whole context, the bound when every occurrence is coded
+    // on its own and the code itself is free
+    pub joint: f64,
+    pub delta_ngrams: Vec<Entropy>,
+    pub strategies: Vec<StrategyBits>,
+}
+
+// Empirical entropy in bits of values weighted by their counts
+fn entropy<K: Eq + Hash>(counts: &HashMap<K, u64>) -> f64 {
+    let total: u64 = counts.values().sum();
+    counts
+        .values()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+fn field_entropy<K: Eq + Hash>(contexts: &[ContextWithCount], field: impl Fn(&Context) -> K) -> f64 {
+    let mut counts = HashMap::new();
+    for context in contexts.iter() {
+        *counts.entry(field(&context.context)).or_insert(0) += context.count;
+    }
+    entropy(&counts)
+}
+
+// Bits per context of coding the deltas of every context, followed by an end
+// marker, with each delta predicted from the `order` before it
+fn ngram_entropy(contexts: &[ContextWithCount], order: usize) -> f64 {
+    let mut counts: HashMap<Vec<Option<ContextDelta>>, HashMap<Option<ContextDelta>, u64>> = HashMap::new();
+    for context in contexts.iter() {
+        let deltas = pack_context(&context.context).expect("context can't be packed");
+        // None pads the start and marks the end of the sequence
+        let mut history = vec![None; order];
+        for symbol in deltas.iter().copied().map(Some).chain(std::iter::once(None)) {
+            *counts.entry(history.clone()).or_default().entry(symbol).or_insert(0) += context.count;
+            if order > 0 {
+                history.remove(0);
+                history.push(symbol);
+            }
+        }
+    }
+
+    let total: u64 = contexts.iter().map(|context| context.count).sum();
+    let bits: f64 = counts
+        .values()
+        .map(|next| next.values().sum::<u64>() as f64 * entropy(next))
+        .sum();
+    bits / total.max(1) as f64
+}
+
+pub fn entropy_report(dataset: &str, contexts: &[ContextWithCount], results: &[SizeResult]) -> EntropyReport {
+    let mut fields = vec![
+        Entropy { name: "stack_size".to_string(), bits: field_entropy(contexts, |ctx| ctx.stack_size) },
+        Entropy { name: "sp_offset".to_string(), bits: field_entropy(contexts, |ctx| ctx.sp_offset) },
+        Entropy { name: "chain_depth".to_string(), bits: field_entropy(contexts, |ctx| ctx.chain_depth) },
+        Entropy { name: "self_type".to_string(), bits: field_entropy(contexts, |ctx| ctx.self_type) },
+    ];
+    for i in 0..MAX_LOCAL_TYPES {
+        fields.push(Entropy {
+            name: format!("local_types[{}]", i),
+            bits: field_entropy(contexts, |ctx| ctx.local_types[i]),
+        });
+    }
+    for i in 0..MAX_TEMP_TYPES {
+        fields.push(Entropy {
+            name: format!("temp_types[{}]", i),
+            bits: field_entropy(contexts, |ctx| ctx.temp_types[i]),
+        });
+    }
+    for i in 0..MAX_TEMP_TYPES {
+        fields.push(Entropy {
+            name: format!("temp_mapping[{}]", i),
+            bits: field_entropy(contexts, |ctx| ctx.temp_mapping[i]),
+        });
+    }
+
+    let joint = field_entropy(contexts, |ctx| ctx.clone());
+    let total_contexts: u64 = contexts.iter().map(|context| context.count).sum();
+
+    EntropyReport {
+        dataset: dataset.to_string(),
+        total_contexts,
+        field_sum: fields.iter().map(|field| field.bits).sum(),
+        fields,
+        joint,
+        delta_ngrams: NGRAM_ORDERS
+            .iter()
+            .map(|&order| Entropy { name: format!("order {}", order), bits: ngram_entropy(contexts, order) })
+            .collect(),
+        strategies: results
+            .iter()
+            .map(|result| {
+                // Per context the strategy stored, which leaves out the ones it can't hold
+                let bits = result.total_bytes as f64 * 8.0 / result.total_contexts.max(1) as f64;
+                let over_bound = if joint == 0.0 { None } else { Some(bits / joint) };
+                StrategyBits { strategy: result.strategy, bits, over_bound }
+            })
+            .collect(),
+    }
+}
+
+// Every number in the report as a (measure, bits) row
+fn rows(report: &EntropyReport) -> Vec<(String, f64)> {
+    let mut rows: Vec<(String, f64)> = report.fields.iter().map(|field| (field.name.clone(), field.bits)).collect();
+    rows.push(("field sum".to_string(), report.field_sum));
+    rows.push(("joint".to_string(), report.joint));
+    rows.extend(report.delta_ngrams.iter().map(|ngram| (format!("deltas {}", ngram.name), ngram.bits)));
+    rows.extend(report.strategies.iter().map(|strategy| (strategy.strategy.to_string(), strategy.bits)));
+    rows
+}
+
+pub fn print_entropy(format: Format, reports: &[EntropyReport]) {
+    match format {
+        Format::Table => {
+            for report in reports.iter() {
+                println!("{} ({} contexts, bits/context)", report.dataset, report.total_contexts);
+                for field in report.fields.iter() {
+                    println!("  {0: <24} {1: >10.3}", field.name, field.bits);
+                }
+                println!("  {0: <24} {1: >10.3}", "field sum", report.field_sum);
+                println!("  {0: <24} {1: >10.3}", "joint", report.joint);
+                for ngram in report.delta_ngrams.iter() {
+                    println!("  {0: <24} {1: >10.3}", format!("deltas {}", ngram.name), ngram.bits);
+                }
+                if !report.strategies.is_empty() {
+                    println!();
+                    println!("  {0: <24} {1: >10} {2: >10}", "strategy", "bits", "x joint");
+                    for strategy in report.strategies.iter() {
+                        let over_bound = strategy.over_bound.map_or("-".to_string(), |over_bound| format!("{:.1}", over_bound));
+                        println!("  {0: <24} {1: >10.1} {2: >10}", strategy.strategy, strategy.bits, over_bound);
+                    }
+                }
+            }
+        }
+        Format::Tsv => {
+            for report in reports.iter() {
+                for (measure, bits) in rows(report) {
+                    println!("{}\t{}\t{:.3}", report.dataset, measure, bits);
+                }
+            }
+        }
+        Format::Json => {
+            println!("{}", serde_json::to_string_pretty(reports).unwrap());
+        }
+        Format::Csv => {
+            println!("dataset,measure,bits");
+            for report in reports.iter() {
+                for (measure, bits) in rows(report) {
+                    println!("{},{},{:.3}", csv_field(&report.dataset), csv_field(&measure), bits);
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::{test_contexts, test_records};
+
+    fn result(total_bytes: usize, total_contexts: u64) -> SizeResult {
+        SizeResult {
+            dataset: "test".to_string(),
+            strategy: "test",
+            type_name: "test",
+            backend: "deepsize",
+            total_bytes,
+            pointer_bytes: total_bytes,
+            storage_bytes: 0,
+            unique_contexts: total_contexts as usize,
+            total_contexts,
+        }
+    }
+
+    #[test]
+    fn a_single_context_has_no_bound() {
+        let records = test_records(&[Context::default(), Context::default()]);
+        let report = entropy_report("test", &records, &[result(16, 2)]);
+        assert_eq!(report.joint, 0.0);
+        assert_eq!(report.strategies[0].bits, 64.0);
+        assert_eq!(report.strategies[0].over_bound, None);
+
+        let records = test_records(&test_contexts(14, 100));
+        let report = entropy_report("test", &records, &[result(800, 100)]);
+        assert!(report.joint > 0.0);
+        assert_eq!(report.strategies[0].over_bound, Some(64.0 / report.joint));
+    }
+}
@@ -1,10 +1,10 @@
 
-use std::{collections::{BTreeMap, hash_map::DefaultHasher, btree_map::Entry}, hash::{Hash, Hasher}, mem::size_of};
+use std::{collections::{BTreeMap, hash_map::DefaultHasher, btree_map::Entry}, hash::{Hash, Hasher}, marker::PhantomData, mem::size_of};
 
 use deepsize::DeepSizeOf;
 use itertools::Itertools;
 
-use crate::{packed_context::{ContextDelta, pack_context}, initial_context::Context, ContextSize};
+use crate::{packed_context::{ContextDelta, pack_context, unpack_context}, initial_context::Context, ContextSize};
 
 
 
@@ -18,48 +18,52 @@ pub struct SinglyLinkedList {
     tail: Option<ContextId>,
 }
 
-pub struct ContextHash {
+// Generic over the hasher so tests can force collisions
+pub struct ContextHash<H = DefaultHasher> {
     hash: BTreeMap<ContextId, SinglyLinkedList>,
+    hasher: PhantomData<H>,
 }
 
-impl ContextHash {
+impl<H: Hasher + Default> ContextHash<H> {
     pub fn new() -> Self {
         ContextHash {
             hash: BTreeMap::new(),
+            hasher: PhantomData,
         }
     }
 
     fn insert(&mut self, ctx: &Context) -> ContextId {
-        let mut deltas = pack_context(ctx).iter().copied().collect_vec();
+        let mut deltas = pack_context(ctx).expect("context can't be packed").iter().copied().collect_vec();
         deltas.reverse();
         deltas.push(ContextDelta::None);
         self.insert_internal(&deltas)
     }
 
+    // The tail is inserted first, so two lists are equal exactly when their
+    // heads and tail ids are. On a collision with a different list, probe the
+    // following ids until we find this one or a free slot.
     fn insert_internal(&mut self, deltas: &[ContextDelta]) -> ContextId {
-        let deltas_hash = self.get_hash(deltas);
-
-        match self.hash.entry(deltas_hash) {
-            Entry::Occupied(_) => {
-                assert!(deltas == self.get_deltas(deltas_hash));
-            }
-            Entry::Vacant(_) => {
-                if deltas.len() == 1 {
-                    self.hash.insert(deltas_hash, SinglyLinkedList {
-                        head: Some(deltas[0]),
-                        tail: None,
-                    });
-                } else {
-                    let tail_hash = self.insert_internal(&deltas[1..]);
-                    self.hash.insert(deltas_hash, SinglyLinkedList {
-                        head: Some(deltas[0]),
-                        tail: Some(tail_hash),
-                    });
+        let tail = if deltas.len() > 1 {
+            Some(self.insert_internal(&deltas[1..]))
+        } else {
+            None
+        };
+        let link = SinglyLinkedList {
+            head: Some(deltas[0]),
+            tail,
+        };
+
+        let mut id = self.get_hash(&link);
+        loop {
+            match self.hash.entry(id) {
+                Entry::Occupied(entry) if *entry.get() == link => return id,
+                Entry::Occupied(_) => id = ContextId(id.0.wrapping_add(1)),
+                Entry::Vacant(entry) => {
+                    entry.insert(link);
+                    return id;
                 }
             }
         }
-        deltas_hash
-
     }
 
     fn get_deltas(&self, hash: ContextId) -> Vec<ContextDelta> {
@@ -77,16 +81,14 @@ impl ContextHash {
         deltas
     }
 
-    fn get_hash(&mut self, deltas: &[ContextDelta]) -> ContextId {
-        let mut hasher = DefaultHasher::new();
-        for delta in deltas {
-            delta.hash(&mut hasher);
-        }
+    fn get_hash(&self, link: &SinglyLinkedList) -> ContextId {
+        let mut hasher = H::default();
+        link.hash(&mut hasher);
         ContextId(hasher.finish())
     }
 }
 
-impl Default for ContextHash {
+impl<H: Hasher + Default> Default for ContextHash<H> {
     fn default() -> Self {
         Self::new()
     }
@@ -94,24 +96,104 @@ impl Default for ContextHash {
 
 
 
-impl ContextSize for ContextHash {
+impl<H: Hasher + Default> ContextSize for ContextHash<H> {
     type Context = Context;
     type Pointer = ContextId;
     type Storage = BTreeMap<ContextId, SinglyLinkedList>;
 
-    fn get_pointer(&self) -> ContextId {
-        *self.hash.iter().next().unwrap().0
-    }
-
     fn get_storage(&self) -> Option<BTreeMap<ContextId, SinglyLinkedList>> {
         Some(self.hash.clone())
     }
 
-    fn get_pointer_size(&self, count: usize) -> usize {
+    fn get_pointer_size(&self, _pointer: &ContextId, count: usize) -> usize {
         size_of::<Self::Pointer>() * count
     }
 
-    fn store_context(&mut self, context: Self::Context) {
-        self.insert(&context);
+    fn store_context(&mut self, context: Self::Context) -> ContextId {
+        self.insert(&context)
+    }
+
+    fn load_context(&self, pointer: &ContextId) -> Context {
+        unpack_context(&self.get_deltas(*pointer))
+    }
+
+    // Only the entries, the BTreeMap nodes add some overhead on top of this
+    fn get_analytic_storage_size(&self) -> usize {
+        self.hash.len() * (size_of::<ContextId>() + size_of::<SinglyLinkedList>())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::{assert_equal_handles, test_contexts, Generator, GeneratorConfig};
+
+    // Only keeps 4 bits of the hash, so nearly every insert collides
+    #[derive(Default)]
+    struct WeakHasher(u64);
+
+    impl Hasher for WeakHasher {
+        fn write(&mut self, bytes: &[u8]) {
+            for &byte in bytes {
+                self.0 = self.0.wrapping_add(byte as u64);
+            }
+        }
+
+        fn finish(&self) -> u64 {
+            self.0 % 16
+        }
+    }
+
+    fn contexts() -> Vec<Context> {
+        let config = GeneratorConfig {
+            seed: 12,
+            contexts: 300,
+            ..Default::default()
+        };
+        Generator::new(config).dataset().into_iter().map(|context| context.context).collect()
+    }
+
+    #[test]
+    fn collisions_round_trip() {
+        let mut weak: ContextHash<WeakHasher> = ContextHash::new();
+        let contexts = contexts();
+        let ids: Vec<ContextId> = contexts.iter().map(|ctx| weak.store_context(ctx.clone())).collect();
+        for (ctx, id) in contexts.iter().zip(ids.iter()) {
+            assert_eq!(&weak.load_context(id), ctx);
+        }
+
+        // Distinct contexts keep distinct ids and equal ones get the same id back
+        assert!(ids.iter().all_unique());
+        for (ctx, id) in contexts.iter().zip(ids.iter()) {
+            assert_eq!(weak.store_context(ctx.clone()), *id);
+        }
+    }
+
+    #[test]
+    fn collisions_share_like_the_default_hasher() {
+        let mut weak: ContextHash<WeakHasher> = ContextHash::new();
+        let mut default: ContextHash = ContextHash::new();
+        for ctx in contexts() {
+            weak.store_context(ctx.clone());
+            default.store_context(ctx);
+        }
+        assert_eq!(weak.hash.len(), default.hash.len());
+    }
+
+    #[test]
+    fn lists_hold_the_packed_deltas() {
+        let mut hash: ContextHash = ContextHash::new();
+        let contexts = test_contexts(4, 1000);
+        let ids: Vec<ContextId> = contexts.iter().map(|ctx| hash.store_context(ctx.clone())).collect();
+        for (ctx, id) in contexts.iter().zip(ids.iter()) {
+            // Lists run from the last delta to the None that ends them
+            let mut deltas = hash.get_deltas(*id);
+            assert_eq!(deltas.pop(), Some(ContextDelta::None));
+            deltas.reverse();
+            assert_eq!(deltas, pack_context(ctx).unwrap().to_vec());
+            assert_eq!(hash.load_context(id), *ctx);
+        }
+        assert_equal_handles(&contexts, &ids);
     }
 }
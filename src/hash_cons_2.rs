@@ -1,21 +1,33 @@
-use std::{rc::Rc, cell::RefCell};
+use std::{rc::Rc, cell::RefCell, hash::{Hash, Hasher}};
 
 use deepsize::DeepSizeOf;
-use once_cell::sync::Lazy;
 
-use crate::{packed_context::{ContextDelta, pack_context}, initial_context::Context, ContextSize};
+use crate::{packed_context::{ContextDelta, pack_context, unpack_context}, initial_context::Context, measure::rc_box_size, ContextSize};
 
 
-
-static mut CONTEXT_ROOT : Lazy<Rc<ContextNode>> =  Lazy::new(|| Rc::new(ContextNode::default()));
-
-#[derive(PartialEq, Debug, DeepSizeOf, Clone)]
+#[derive(Debug, DeepSizeOf, Clone)]
 pub struct ContextNode {
     delta: ContextDelta,
     parent: Option<Rc<ContextNode>>,
     children: RefCell<Vec<Rc<ContextNode>>>,
 }
 
+// Equal contexts end at the same node of a trie, so nodes compare and hash by
+// address rather than walking the whole trie
+impl PartialEq for ContextNode {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for ContextNode {}
+
+impl Hash for ContextNode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::ptr::hash(self, state)
+    }
+}
+
 impl Default for ContextNode {
     fn default() -> Self {
         ContextNode {
@@ -37,6 +49,16 @@ impl ContextNode {
         count
     }
 
+    // Bytes of the node allocations and their children Vecs below ctx
+    pub fn get_analytic_size(ctx: &Rc<ContextNode>) -> usize {
+        let children = ctx.children.borrow();
+        let mut size = rc_box_size::<ContextNode>() + children.capacity() * std::mem::size_of::<Rc<ContextNode>>();
+        for child in children.iter() {
+            size += ContextNode::get_analytic_size(child);
+        }
+        size
+    }
+
     pub fn _dump_nodes(ctx: &Rc<ContextNode>, indent: usize) {
         for _ in 0..indent {
             print!("  ");
@@ -48,63 +70,209 @@ impl ContextNode {
     }
 
 
+    // Walk from the node back up to the root to recover the deltas
+    pub fn decompress(ctx: &Rc<ContextNode>) -> Context {
+        let mut deltas = vec![];
+        let mut node = Some(ctx);
+        while let Some(current) = node {
+            deltas.push(current.delta);
+            node = current.parent.as_ref();
+        }
+        unpack_context(&deltas)
+    }
 
-    pub fn compress(ctx: &Context) -> Rc<ContextNode> {
-        let mut parent = unsafe { CONTEXT_ROOT.clone() };
-        let mut node = parent.clone();
-
-        let deltas = pack_context(ctx);
-        for delta in deltas.iter() {
-            let mut found = node.clone();
-            for child in parent.children.borrow().iter() {
-                if child.delta == *delta {
-                    found = child.clone();
-                    break;
-                }
-            }
-            if parent.delta == found.delta {
-            //if parent == found {
-                let new_node = Rc::new(ContextNode {
-                    delta: *delta,
-                    parent: Some(parent.clone()),
-                    children: RefCell::new(vec![]),
-                });
-                parent.children.borrow_mut().push(new_node.clone());
-                found = new_node;
-            }
-            node = found.clone();
-            parent = found.clone();
+    // Find the child with the given delta, adding it if there isn't one
+    fn get_or_insert_child(parent: &Rc<ContextNode>, delta: ContextDelta) -> Rc<ContextNode> {
+        if let Some(child) = parent.children.borrow().iter().find(|child| child.delta == delta) {
+            return child.clone();
         }
+        let child = Rc::new(ContextNode {
+            delta,
+            parent: Some(parent.clone()),
+            children: RefCell::new(vec![]),
+        });
+        parent.children.borrow_mut().push(child.clone());
+        child
+    }
 
+    // Follow the deltas of the context down from root, so contexts that share
+    // a prefix of deltas share those nodes
+    pub fn compress(root: &Rc<ContextNode>, ctx: &Context) -> Rc<ContextNode> {
+        let deltas = pack_context(ctx).expect("context can't be packed");
+        let mut node = root.clone();
+        for &delta in deltas.iter() {
+            node = Self::get_or_insert_child(&node, delta);
+        }
         node
     }
 }
 
 
-impl ContextSize for ContextNode {
+// A trie of contexts owned by one strategy instance
+pub struct ContextTrie {
+    root: Rc<ContextNode>,
+}
+
+impl Default for ContextTrie {
+    fn default() -> Self {
+        ContextTrie {
+            root: Rc::new(ContextNode::default()),
+        }
+    }
+}
+
+impl ContextTrie {
+    pub fn _get_node_count(&self) -> usize {
+        ContextNode::_get_node_count(&self.root)
+    }
+}
+
+// Children and parents point at each other, so take the children out to break
+// the cycles. Nodes still referenced by handles stay alive through their parents.
+impl Drop for ContextTrie {
+    fn drop(&mut self) {
+        let mut nodes = vec![self.root.clone()];
+        while let Some(node) = nodes.pop() {
+            nodes.extend(std::mem::take(&mut *node.children.borrow_mut()));
+        }
+    }
+}
+
+impl ContextSize for ContextTrie {
     type Context = Context;
 
     type Pointer = Rc<ContextNode>;
 
     type Storage = Rc<ContextNode>;
 
-    fn get_pointer(&self) -> Self::Pointer {
-        Rc::new(self.clone())
+    fn get_storage(&self) -> Option<Self::Storage> {
+        Some(self.root.clone())
     }
 
-    fn get_storage(&self) -> Option<Self::Storage> {
-        Some(unsafe { CONTEXT_ROOT.clone() })
+    fn store_context(&mut self, context: Self::Context) -> Self::Pointer {
+        ContextNode::compress(&self.root, &context)
     }
 
-    fn store_context(&mut self, context: Self::Context) {
-        Self::compress(&context);
+    fn load_context(&self, pointer: &Self::Pointer) -> Context {
+        ContextNode::decompress(pointer)
     }
 
-    fn get_pointer_size(&self, count: usize) -> usize {
+    fn get_analytic_storage_size(&self) -> usize {
+        ContextNode::get_analytic_size(&self.root)
+    }
+
+    fn get_pointer_size(&self, _pointer: &Self::Pointer, count: usize) -> usize {
         // Don't do deepsize here because we are just referencing
         let pointer_size = std::mem::size_of::<Self::Pointer>();
         pointer_size * count
     }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{initial_context::{TempMapping, Type}, synthetic::test_contexts};
+
+    fn contexts() -> Vec<Context> {
+        let mut contexts = vec![Context::default()];
+
+        let mut ctx = Context {
+            self_type: Type::UnknownHeap,
+            ..Default::default()
+        };
+        contexts.push(ctx.clone());
+
+        // Extends the previous one, so its nodes are a prefix of this one
+        ctx.chain_depth = 1;
+        contexts.push(ctx.clone());
+
+        // Shares the SelfType node and branches off after it
+        ctx.chain_depth = 0;
+        ctx.local_types[0] = Type::Fixnum;
+        contexts.push(ctx.clone());
+
+        ctx.stack_size = 2;
+        ctx.sp_offset = 2;
+        ctx.temp_types[0] = Type::CString;
+        ctx.temp_mapping[1] = TempMapping::MapToLocal(0);
+        contexts.push(ctx.clone());
+
+        // Doesn't share anything with the others
+        contexts.push(Context {
+            stack_size: 1000,
+            sp_offset: -1000,
+            ..Default::default()
+        });
+
+        contexts
+    }
+
+    // Every non-empty prefix of every context's deltas, plus the root
+    fn distinct_prefixes(contexts: &[Context]) -> usize {
+        let mut prefixes = HashSet::new();
+        for ctx in contexts.iter() {
+            let deltas = pack_context(ctx).unwrap();
+            for len in 1..=deltas.len() {
+                prefixes.insert(deltas[..len].to_vec());
+            }
+        }
+        prefixes.len() + 1
+    }
 
+    #[test]
+    fn node_count_is_distinct_prefixes() {
+        let contexts = contexts();
+        let mut trie = ContextTrie::default();
+        for ctx in contexts.iter() {
+            trie.store_context(ctx.clone());
+        }
+        assert_eq!(trie._get_node_count(), distinct_prefixes(&contexts));
 
+        // Storing them all again doesn't add anything
+        for ctx in contexts.iter() {
+            trie.store_context(ctx.clone());
+        }
+        assert_eq!(trie._get_node_count(), distinct_prefixes(&contexts));
+    }
+
+    #[test]
+    fn equal_contexts_share_a_node_and_load_back() {
+        let mut trie = ContextTrie::default();
+        let handles: Vec<_> = contexts().into_iter().map(|ctx| trie.store_context(ctx)).collect();
+        for (ctx, handle) in contexts().into_iter().zip(handles.iter()) {
+            assert!(Rc::ptr_eq(handle, &trie.store_context(ctx.clone())));
+            assert_eq!(trie.load_context(handle), ctx);
+        }
+    }
+
+    #[test]
+    fn tries_are_independent() {
+        let mut first = ContextTrie::default();
+        let mut second = ContextTrie::default();
+        for ctx in contexts() {
+            first.store_context(ctx);
+        }
+        assert_eq!(second._get_node_count(), 1);
+
+        let ctx = contexts().pop().unwrap();
+        let handle = second.store_context(ctx.clone());
+        assert_eq!(second._get_node_count(), distinct_prefixes(std::slice::from_ref(&ctx)));
+
+        // Handles keep working after their trie is gone
+        drop(second);
+        assert_eq!(ContextNode::decompress(&handle), ctx);
+    }
+
+    #[test]
+    fn generated_contexts_share_prefixes() {
+        let contexts = test_contexts(5, 1000);
+        let mut trie = ContextTrie::default();
+        for ctx in contexts.iter() {
+            trie.store_context(ctx.clone());
+        }
+        assert_eq!(trie._get_node_count(), distinct_prefixes(&contexts));
+    }
 }
@@ -0,0 +1,228 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    mem::size_of,
+};
+
+use deepsize::DeepSizeOf;
+
+use crate::{
+    initial_context::{Context, ContextWithCount},
+    measure::hash_table_size,
+    packed_context::{pack_context, unpack_context, ContextDelta},
+    ContextSize,
+};
+
+// Huffman coded deltas of a context, most significant bit first, ending with
+// the code for ContextDelta::None
+pub type HuffmanContext = Box<[u8]>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DeepSizeOf)]
+pub struct Code {
+    bits: u64,
+    len: u8,
+}
+
+// Canonical Huffman code over the deltas pack_context produces. Decoding only
+// needs the symbols ordered by code length and how many codes there are of
+// each length; the encoder map is what you'd keep around to encode new contexts.
+#[derive(Clone, Debug, Default, DeepSizeOf)]
+pub struct HuffmanCoder {
+    symbols: Vec<ContextDelta>,
+    // Number of codes of each length, indexed by length
+    counts: Vec<u32>,
+    encoder: HashMap<ContextDelta, Code>,
+}
+
+// Every context is coded as its deltas followed by this
+const END: ContextDelta = ContextDelta::None;
+
+fn context_deltas(ctx: &Context) -> impl Iterator<Item = ContextDelta> {
+    let packed = pack_context(ctx).expect("context can't be packed");
+    packed.into_vec().into_iter().chain(std::iter::once(END))
+}
+
+// Code length of every symbol, from how often it's used
+fn code_lengths(weights: &HashMap<ContextDelta, u64>) -> HashMap<ContextDelta, u8> {
+    if weights.len() == 1 {
+        return weights.keys().map(|&symbol| (symbol, 1)).collect();
+    }
+
+    // Merge the two lightest trees until one is left, remembering each node's parent
+    let symbols: Vec<ContextDelta> = weights.keys().copied().collect();
+    let mut parents: Vec<usize> = vec![usize::MAX; symbols.len()];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = symbols
+        .iter()
+        .enumerate()
+        .map(|(i, symbol)| Reverse((weights[symbol], i)))
+        .collect();
+    while heap.len() > 1 {
+        let Reverse((a_weight, a)) = heap.pop().unwrap();
+        let Reverse((b_weight, b)) = heap.pop().unwrap();
+        let node = parents.len();
+        parents.push(usize::MAX);
+        parents[a] = node;
+        parents[b] = node;
+        heap.push(Reverse((a_weight + b_weight, node)));
+    }
+
+    symbols
+        .iter()
+        .enumerate()
+        .map(|(i, &symbol)| {
+            let mut len = 0;
+            let mut node = i;
+            while parents[node] != usize::MAX {
+                node = parents[node];
+                len += 1;
+            }
+            (symbol, len)
+        })
+        .collect()
+}
+
+impl HuffmanCoder {
+    // Build the code from how often every delta occurs in the dataset
+    pub fn train(contexts: &[ContextWithCount]) -> Self {
+        let mut weights: HashMap<ContextDelta, u64> = HashMap::new();
+        for context in contexts.iter() {
+            for delta in context_deltas(&context.context) {
+                *weights.entry(delta).or_insert(0) += context.count.max(1);
+            }
+        }
+        if weights.is_empty() {
+            return HuffmanCoder::default();
+        }
+
+        let lengths = code_lengths(&weights);
+        let mut symbols: Vec<ContextDelta> = lengths.keys().copied().collect();
+        symbols.sort_by_key(|symbol| (lengths[symbol], *symbol));
+        assert!(lengths.values().all(|&len| len <= 64), "Huffman code longer than 64 bits");
+
+        let max_len = lengths.values().copied().max().unwrap() as usize;
+        let mut counts = vec![0; max_len + 1];
+        for &len in lengths.values() {
+            counts[len as usize] += 1;
+        }
+
+        // Canonical codes: consecutive within a length, shifting left for longer ones
+        let mut encoder = HashMap::with_capacity(symbols.len());
+        let mut bits = 0u64;
+        let mut len = 0;
+        for &symbol in symbols.iter() {
+            let symbol_len = lengths[&symbol];
+            bits <<= symbol_len - len;
+            len = symbol_len;
+            encoder.insert(symbol, Code { bits, len });
+            bits += 1;
+        }
+
+        symbols.shrink_to_fit();
+        HuffmanCoder { symbols, counts, encoder }
+    }
+
+    pub fn encode(&self, ctx: &Context) -> HuffmanContext {
+        let mut bytes = vec![];
+        let mut used = 0;
+        for delta in context_deltas(ctx) {
+            let code = self.encoder.get(&delta).expect("delta wasn't seen in training");
+            for i in (0..code.len).rev() {
+                if used % 8 == 0 {
+                    bytes.push(0);
+                }
+                let bit = ((code.bits >> i) & 1) as u8;
+                *bytes.last_mut().unwrap() |= bit << (7 - used % 8);
+                used += 1;
+            }
+        }
+        bytes.into_boxed_slice()
+    }
+
+    pub fn decode(&self, encoded: &[u8]) -> Context {
+        let mut bits = encoded.iter().flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1));
+        let mut deltas = vec![];
+        loop {
+            // Walk down the lengths until the code falls within the ones of that length
+            let mut code = 0u64;
+            let mut first = 0u64;
+            let mut index = 0u64;
+            let mut symbol = None;
+            for &count in self.counts.iter().skip(1) {
+                code |= bits.next().expect("truncated Huffman code") as u64;
+                if code - first < count as u64 {
+                    symbol = Some(self.symbols[(index + code - first) as usize]);
+                    break;
+                }
+                index += count as u64;
+                first = (first + count as u64) << 1;
+                code <<= 1;
+            }
+            match symbol.expect("invalid Huffman code") {
+                END => break,
+                delta => deltas.push(delta),
+            }
+        }
+        unpack_context(&deltas)
+    }
+
+    // Bytes of the canonical code and the encoder, worked out from the layouts
+    pub fn get_analytic_size(&self) -> usize {
+        self.symbols.capacity() * size_of::<ContextDelta>()
+            + self.counts.capacity() * size_of::<u32>()
+            + hash_table_size::<(ContextDelta, Code)>(self.encoder.capacity())
+    }
+}
+
+
+#[derive(Default)]
+pub struct HuffmanStorage {
+    coder: HuffmanCoder,
+}
+
+impl ContextSize for HuffmanStorage {
+    type Context = Context;
+    type Pointer = HuffmanContext;
+    type Storage = HuffmanCoder;
+
+    fn get_storage(&self) -> Option<HuffmanCoder> {
+        Some(self.coder.clone())
+    }
+
+    const NEEDS_TRAINING: bool = true;
+
+    fn train(&mut self, contexts: &[ContextWithCount]) {
+        self.coder = HuffmanCoder::train(contexts);
+    }
+
+    fn store_context(&mut self, context: Context) -> HuffmanContext {
+        self.coder.encode(&context)
+    }
+
+    fn load_context(&self, pointer: &HuffmanContext) -> Context {
+        self.coder.decode(pointer)
+    }
+
+    fn get_analytic_pointer_size(&self, pointer: &HuffmanContext) -> usize {
+        size_of::<HuffmanContext>() + pointer.len()
+    }
+
+    fn get_analytic_storage_size(&self) -> usize {
+        self.coder.get_analytic_size()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::{assert_roundtrip, test_records};
+
+    #[test]
+    fn a_single_symbol_gets_a_one_bit_code() {
+        let records = test_records(&[Context::default(), Context::default()]);
+        let (storage, handles) = assert_roundtrip::<HuffmanStorage>(&records);
+        assert_eq!(storage.coder.symbols, [END]);
+        assert_eq!(storage.coder.counts, [0, 1]);
+        assert_eq!(*handles[0], [0]);
+    }
+}
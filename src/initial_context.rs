@@ -1,4 +1,4 @@
-use deepsize::DeepSizeOf;
+use deepsize::{Context as DeepSizeContext, DeepSizeOf};
 use serde::{Serialize, Deserialize};
 
 use crate::ContextSize;
@@ -10,8 +10,9 @@ pub const MAX_TEMP_TYPES: usize = 8;
 pub const MAX_LOCAL_TYPES: usize = 8;
 
 // Represent the type of a value (local/stack/self) in YJIT
-#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, Serialize, Deserialize, DeepSizeOf)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Default, Hash, Serialize, Deserialize, DeepSizeOf)]
 pub enum Type {
+    #[default]
     Unknown,
     UnknownImm,
     UnknownHeap,
@@ -35,36 +36,130 @@ pub enum Type {
                      // the current surrounding cfp
 }
 
-// Default initialization
-impl Default for Type {
-    fn default() -> Self {
-        Type::Unknown
+impl Type {
+    // Every variant, in declaration order
+    pub const ALL: [Type; 16] = [
+        Type::Unknown,
+        Type::UnknownImm,
+        Type::UnknownHeap,
+        Type::Nil,
+        Type::True,
+        Type::False,
+        Type::Fixnum,
+        Type::Flonum,
+        Type::Hash,
+        Type::ImmSymbol,
+        Type::HeapSymbol,
+        Type::TString,
+        Type::CString,
+        Type::TArray,
+        Type::CArray,
+        Type::BlockParamProxy,
+    ];
+
+    // Whether the value is an immediate
+    pub fn is_imm(&self) -> bool {
+        matches!(
+            self,
+            Type::UnknownImm | Type::Nil | Type::True | Type::False | Type::Fixnum | Type::Flonum | Type::ImmSymbol
+        )
+    }
+
+    // Whether the value is a heap object
+    pub fn is_heap(&self) -> bool {
+        matches!(
+            self,
+            Type::UnknownHeap
+                | Type::TArray
+                | Type::CArray
+                | Type::Hash
+                | Type::HeapSymbol
+                | Type::TString
+                | Type::CString
+                | Type::BlockParamProxy
+        )
+    }
+
+    // How far a value of this type is from dst, as YJIT works it out when it
+    // looks for a block version to reuse: 0 for the same type, 1 when this is
+    // a more specific type that dst covers
+    pub fn diff(self, dst: Self) -> TypeDiff {
+        // Perfect match, difference is zero
+        if self == dst {
+            return TypeDiff::Compatible(0);
+        }
+
+        // Any type can flow into an unknown type
+        if dst == Type::Unknown {
+            return TypeDiff::Compatible(1);
+        }
+
+        // A CString is also a TString, and a CArray a TArray
+        if (self == Type::CString && dst == Type::TString) || (self == Type::CArray && dst == Type::TArray) {
+            return TypeDiff::Compatible(1);
+        }
+
+        // Specific heap type into unknown heap, immediate into unknown immediate
+        if (self.is_heap() && dst == Type::UnknownHeap) || (self.is_imm() && dst == Type::UnknownImm) {
+            return TypeDiff::Compatible(1);
+        }
+
+        TypeDiff::Incompatible
+    }
+
+    // Upgrade this type to a more specific compatible one, like YJIT does after
+    // a type guard passes
+    pub fn upgrade(&mut self, src: Self) {
+        // src has to be at least as specific as what we know already
+        assert!(src.diff(*self) != TypeDiff::Incompatible, "can't upgrade {:?} to {:?}", self, src);
+        *self = src;
     }
 }
 
+// Result of Type::diff and Context::diff
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TypeDiff {
+    // The source can use code compiled for the destination, the number is how
+    // much information that gives up
+    Compatible(usize),
+    Incompatible,
+}
 
 // Potential mapping of a value on the temporary stack to
 // self, a local variable or constant so that we can track its type
-#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, Serialize, Deserialize, DeepSizeOf)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, Default, Serialize, Deserialize, DeepSizeOf)]
 #[allow(clippy::enum_variant_names)]
 pub enum TempMapping {
+    #[default]
     MapToStack,     // Normal stack value
     MapToSelf,      // Temp maps to the self operand
     MapToLocal(u8), // Temp maps to a local variable with index
 }
 
-impl Default for TempMapping {
-    fn default() -> Self {
-        TempMapping::MapToStack
+// serde and deepsize only implement their traits for arrays of fixed lengths,
+// so arrays whose length is a const parameter go through these
+pub mod serde_array {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T: Serialize, S: Serializer, const N: usize>(array: &[T; N], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(array.iter())
     }
-}
 
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<[T; N], D::Error> {
+        let items = Vec::<T>::deserialize(deserializer)?;
+        let len = items.len();
+        items.try_into().map_err(|_| D::Error::invalid_length(len, &format!("an array of {}", N).as_str()))
+    }
+}
 
 /// Code generation context
 /// Contains information we can use to specialize/optimize code
 /// There are a lot of context objects so we try to keep the size small.
-#[derive(Clone, Default, PartialEq, Eq, Hash, Debug, Serialize, Deserialize, DeepSizeOf)]
-pub struct Context {
+///
+/// Generic over how many locals and temps are tracked so we can see what
+/// other limits would cost, the defaults are what YJIT uses.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct Context<const LOCALS: usize = MAX_LOCAL_TYPES, const TEMPS: usize = MAX_TEMP_TYPES> {
     // Number of values currently on the temporary stack
     pub stack_size: u16,
 
@@ -76,23 +171,142 @@ pub struct Context {
     pub chain_depth: u8,
 
     // Local variable types we keep track of
-    pub local_types: [Type; MAX_LOCAL_TYPES],
+    #[serde(with = "serde_array")]
+    pub local_types: [Type; LOCALS],
 
     // Temporary variable types we keep track of
-    pub temp_types: [Type; MAX_TEMP_TYPES],
+    #[serde(with = "serde_array")]
+    pub temp_types: [Type; TEMPS],
 
     // Type we track for self
     pub self_type: Type,
 
     // Mapping of temp stack entries to types we track
-    pub temp_mapping: [TempMapping; MAX_TEMP_TYPES],
+    #[serde(with = "serde_array")]
+    pub temp_mapping: [TempMapping; TEMPS],
+}
+
+impl<const LOCALS: usize, const TEMPS: usize> Default for Context<LOCALS, TEMPS> {
+    fn default() -> Self {
+        Context {
+            stack_size: 0,
+            sp_offset: 0,
+            chain_depth: 0,
+            local_types: [Type::Unknown; LOCALS],
+            temp_types: [Type::Unknown; TEMPS],
+            self_type: Type::Unknown,
+            temp_mapping: [TempMapping::MapToStack; TEMPS],
+        }
+    }
 }
 
+// Every field is stored inline
+impl<const LOCALS: usize, const TEMPS: usize> DeepSizeOf for Context<LOCALS, TEMPS> {
+    fn deep_size_of_children(&self, _context: &mut DeepSizeContext) -> usize {
+        0
+    }
+}
+
+impl<const LOCALS: usize, const TEMPS: usize> Context<LOCALS, TEMPS> {
+    // The mapping of the temp stack_idx slots from the bottom of the stack and
+    // the type it gives the temp
+    fn temp_opnd(&self, stack_idx: usize) -> (TempMapping, Type) {
+        let mapping = self.temp_mapping[stack_idx];
+        let opnd_type = match mapping {
+            TempMapping::MapToSelf => self.self_type,
+            TempMapping::MapToStack => self.temp_types[stack_idx],
+            TempMapping::MapToLocal(idx) => self.local_types[idx as usize],
+        };
+        (mapping, opnd_type)
+    }
+
+    // How far self, the context at the end of a predecessor, is from dst, the
+    // context of an existing block version. Compatible means that version can
+    // be jumped to instead of compiling a new one.
+    pub fn diff(&self, dst: &Self) -> TypeDiff {
+        let src = self;
+
+        // Can only look up the first version in the chain, and blocks with
+        // depth > 0 always produce new versions since sidechains can't overlap
+        if dst.chain_depth != 0 || src.chain_depth != 0 {
+            return TypeDiff::Incompatible;
+        }
+
+        if dst.stack_size != src.stack_size || dst.sp_offset != src.sp_offset {
+            return TypeDiff::Incompatible;
+        }
 
-#[derive(Debug, Serialize, Deserialize)]
+        let mut diff = 0;
+        let types = std::iter::once((src.self_type, dst.self_type))
+            .chain(src.local_types.iter().copied().zip(dst.local_types.iter().copied()));
+        for (src_type, dst_type) in types {
+            match src_type.diff(dst_type) {
+                TypeDiff::Compatible(type_diff) => diff += type_diff,
+                TypeDiff::Incompatible => return TypeDiff::Incompatible,
+            }
+        }
+
+        // Temps past the ones we track are stack values of unknown type on
+        // both sides
+        for stack_idx in 0..(src.stack_size as usize).min(TEMPS) {
+            let (src_mapping, src_type) = src.temp_opnd(stack_idx);
+            let (dst_mapping, dst_type) = dst.temp_opnd(stack_idx);
+
+            if src_mapping != dst_mapping {
+                if dst_mapping == TempMapping::MapToStack {
+                    // We can safely drop where the temp came from
+                    diff += 1;
+                } else {
+                    return TypeDiff::Incompatible;
+                }
+            }
+
+            match src_type.diff(dst_type) {
+                TypeDiff::Compatible(type_diff) => diff += type_diff,
+                TypeDiff::Incompatible => return TypeDiff::Incompatible,
+            }
+        }
+
+        TypeDiff::Compatible(diff)
+    }
+
+    // The same context with other limits. Slots past the new ones are dropped
+    // and temps mapped to a local that isn't tracked anymore go back to being
+    // stack values, like YJIT does when it stops tracking a local.
+    pub fn resize<const NEW_LOCALS: usize, const NEW_TEMPS: usize>(&self) -> Context<NEW_LOCALS, NEW_TEMPS> {
+        let mut ctx = Context {
+            stack_size: self.stack_size,
+            sp_offset: self.sp_offset,
+            chain_depth: self.chain_depth,
+            self_type: self.self_type,
+            ..Default::default()
+        };
+        for (new, &old) in ctx.local_types.iter_mut().zip(self.local_types.iter()) {
+            *new = old;
+        }
+        for (new, &old) in ctx.temp_types.iter_mut().zip(self.temp_types.iter()) {
+            *new = old;
+        }
+        for (new, &old) in ctx.temp_mapping.iter_mut().zip(self.temp_mapping.iter()) {
+            *new = match old {
+                TempMapping::MapToLocal(index) if index as usize >= NEW_LOCALS => TempMapping::MapToStack,
+                mapping => mapping,
+            };
+        }
+        ctx
+    }
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ContextWithCount {
     pub context: Context,
     pub count: u64,
+    // Block the context was recorded for, and the block that led to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<u64>,
 }
 
 
@@ -101,11 +315,102 @@ impl ContextSize for Context {
     type Context = Context;
     type Pointer = Context;
     type Storage = ();
-    fn get_pointer(&self) -> Context {
-        self.clone()
-    }
 
     fn get_storage(&self) -> Option<()> {
         None
     }
+
+    fn store_context(&mut self, context: Context) -> Context {
+        context
+    }
+
+    fn load_context(&self, pointer: &Context) -> Context {
+        pointer.clone()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::{test_contexts, Rng};
+
+    #[test]
+    fn type_diff_is_a_partial_order() {
+        for a in Type::ALL {
+            assert_eq!(a.diff(a), TypeDiff::Compatible(0));
+            assert_ne!(a.diff(Type::Unknown), TypeDiff::Incompatible);
+            for b in Type::ALL {
+                // Only equal types flow into each other both ways
+                if a != b {
+                    assert!(a.diff(b) == TypeDiff::Incompatible || b.diff(a) == TypeDiff::Incompatible);
+                }
+                for c in Type::ALL {
+                    if a.diff(b) != TypeDiff::Incompatible && b.diff(c) != TypeDiff::Incompatible {
+                        assert_ne!(a.diff(c), TypeDiff::Incompatible, "{:?} {:?} {:?}", a, b, c);
+                    }
+                }
+            }
+        }
+        assert_eq!(Type::CString.diff(Type::TString), TypeDiff::Compatible(1));
+        assert_eq!(Type::Fixnum.diff(Type::UnknownImm), TypeDiff::Compatible(1));
+        assert_eq!(Type::Fixnum.diff(Type::UnknownHeap), TypeDiff::Incompatible);
+        assert_eq!(Type::TString.diff(Type::CString), TypeDiff::Incompatible);
+    }
+
+    #[test]
+    fn upgrade_only_narrows() {
+        let mut ty = Type::Unknown;
+        ty.upgrade(Type::UnknownHeap);
+        ty.upgrade(Type::TString);
+        ty.upgrade(Type::CString);
+        assert_eq!(ty, Type::CString);
+        assert!(std::panic::catch_unwind(move || ty.upgrade(Type::Fixnum)).is_err());
+    }
+
+    #[test]
+    fn forgetting_types_gives_a_compatible_context() {
+        let mut rng = Rng::new(8);
+        for ctx in test_contexts(8, 1000).into_iter().filter(|ctx| ctx.chain_depth == 0) {
+            assert_eq!(ctx.diff(&ctx), TypeDiff::Compatible(0));
+
+            let mut general = ctx.clone();
+            for ty in general.local_types.iter_mut().chain(std::iter::once(&mut general.self_type)) {
+                if rng.chance(0.3) {
+                    *ty = Type::Unknown;
+                }
+            }
+            for i in 0..MAX_TEMP_TYPES.min(general.stack_size as usize) {
+                if rng.chance(0.3) {
+                    general.temp_mapping[i] = TempMapping::MapToStack;
+                    general.temp_types[i] = Type::Unknown;
+                }
+            }
+            // Locals a temp maps to may have been forgotten as well, which only
+            // makes that temp less specific
+            let forward = ctx.diff(&general);
+            assert_ne!(forward, TypeDiff::Incompatible, "{:?} {:?}", ctx, general);
+            if forward != TypeDiff::Compatible(0) {
+                assert_eq!(general.diff(&ctx), TypeDiff::Incompatible);
+            }
+        }
+    }
+
+    #[test]
+    fn context_diff_needs_the_same_stack_and_no_chain() {
+        let ctx: Context = Context { stack_size: 2, sp_offset: 2, ..Default::default() };
+        assert_eq!(ctx.diff(&Context { stack_size: 3, ..ctx.clone() }), TypeDiff::Incompatible);
+        assert_eq!(ctx.diff(&Context { sp_offset: 1, ..ctx.clone() }), TypeDiff::Incompatible);
+        assert_eq!(ctx.diff(&Context { chain_depth: 1, ..ctx.clone() }), TypeDiff::Incompatible);
+        assert_eq!(Context { chain_depth: 1, ..ctx.clone() }.diff(&ctx), TypeDiff::Incompatible);
+
+        // A temp can drop its mapping but not gain one
+        let mut mapped = ctx.clone();
+        mapped.local_types[0] = Type::Fixnum;
+        mapped.temp_mapping[1] = TempMapping::MapToLocal(0);
+        let mut unmapped = mapped.clone();
+        unmapped.temp_mapping[1] = TempMapping::MapToStack;
+        assert_eq!(mapped.diff(&unmapped), TypeDiff::Compatible(2));
+        assert_eq!(unmapped.diff(&mapped), TypeDiff::Incompatible);
+    }
 }
@@ -1,26 +1,55 @@
+mod arena;
+mod bench;
+mod binary_dataset;
+mod bit_packed;
+mod cli;
 mod compact_temp_mapping;
+mod compare;
+mod debug_dump;
 mod deduplicated;
+mod entropy;
 mod hash_cons;
+mod huffman;
 mod initial_context;
+mod measure;
 mod packed_context;
+mod parent_delta;
+mod report;
+mod stats;
+mod sweep;
+mod synthetic;
+mod upstream_context;
 mod hash_cons_2;
-use std::rc::Rc;
+use std::{borrow::Cow, collections::{hash_map::DefaultHasher, HashSet}, fs::{self, File}, hash::{Hash, Hasher}, io::{BufRead, BufReader, Read}, mem::size_of, panic, process, rc::Rc, slice};
 
-use crate::{deduplicated::{RcLite, RawPointer}, initial_context::ContextWithCount};
+use crate::{bench::{BenchConfig, Timing}, cli::{Args, CliError, Command}, deduplicated::RcLite, initial_context::ContextWithCount, measure::{BackendKind, Measurement}, report::{BenchResult, DatasetCounts, SizeResult}};
 use deepsize::DeepSizeOf;
-use itertools::Itertools;
 
 
 trait ContextSize {
     type Context: DeepSizeOf + Clone;
-    type Pointer: DeepSizeOf;
+    // Handle returned by store_context that can be turned back into a Context
+    type Pointer: DeepSizeOf + Eq + Hash;
     type Storage: DeepSizeOf;
-    fn get_pointer(&self) -> Self::Pointer;
     fn get_storage(&self) -> Option<Self::Storage>;
-    fn store_context(&mut self, _context: Self::Context) {}
+    // Look at the whole dataset before anything is stored, for strategies that
+    // are tuned to the data
+    fn train(&mut self, _contexts: &[ContextWithCount]) {}
+    // Whether storing contexts without training first would be meaningless
+    const NEEDS_TRAINING: bool = false;
+    // Most unique contexts the strategy can hold
+    const CAPACITY: usize = usize::MAX;
+    fn store_context(&mut self, context: Self::Context) -> Self::Pointer;
+    fn load_context(&self, pointer: &Self::Pointer) -> initial_context::Context;
 
-    fn get_pointer_size(&self, count: usize) -> usize {
-        let pointer_size = self.get_pointer().deep_size_of();
+    // Whether the context can be stored without losing anything, the contexts
+    // that can't are left out of the dataset and counted
+    fn holds(_context: &initial_context::Context) -> bool {
+        true
+    }
+
+    fn get_pointer_size(&self, pointer: &Self::Pointer, count: usize) -> usize {
+        let pointer_size = pointer.deep_size_of();
         let total_size: usize = pointer_size * count;
         total_size
     }
@@ -29,53 +58,533 @@ trait ContextSize {
         let storage = self.get_storage();
         storage.map(|x| x.deep_size_of()).unwrap_or(0)
     }
+
+    // Size of a pointer worked out from the type layouts, including any heap
+    // memory that only this pointer owns
+    fn get_analytic_pointer_size(&self, _pointer: &Self::Pointer) -> usize {
+        size_of::<Self::Pointer>()
+    }
+
+    // Size of the shared storage worked out from the type layouts
+    fn get_analytic_storage_size(&self) -> usize {
+        0
+    }
 }
 
 
-fn total_size<T>(contexts: &[initial_context::ContextWithCount]) -> usize
+// Create a strategy that has been trained on the dataset it's about to store
+fn new_strategy<T>(contexts: &[ContextWithCount]) -> T
 where
     T: ContextSize + Default,
-    <T as ContextSize>::Context: From<initial_context::Context>,
 {
     let mut t = T::default();
-    let mut total_size = 0;
-    for context in contexts.iter() {
-        let new_context: T::Context = Into::into(context.context.clone());
-        for _ in 0..context.count {
-            t.store_context(new_context.clone());
+    t.train(contexts);
+    t
+}
+
+// Store every context, then load each handle back, returning how many didn't
+// come back unchanged. Loading happens after all the stores so that handles
+// invalidated by later stores are caught too.
+fn check_roundtrip<T>(contexts: &[initial_context::ContextWithCount]) -> usize
+where
+    T: ContextSize + Default,
+    <T as ContextSize>::Context: From<initial_context::Context>,
+{
+    let mut t: T = new_strategy(contexts);
+    let pointers: Vec<T::Pointer> = contexts
+        .iter()
+        .map(|context| t.store_context(context.context.clone().into()))
+        .collect();
+
+    contexts
+        .iter()
+        .zip(pointers.iter())
+        .filter(|(context, pointer)| t.load_context(pointer) != context.context)
+        .count()
+}
+
+
+pub struct Strategy {
+    // Short name used to select the strategy on the command line
+    pub name: &'static str,
+    pub type_name: &'static str,
+    // Only run when selected by name
+    pub optional: bool,
+    pub capacity: usize,
+    pub holds: fn(&initial_context::Context) -> bool,
+    pub measure: fn(BackendKind, &[ContextWithCount]) -> Measurement,
+    pub check_roundtrip: fn(&[ContextWithCount]) -> usize,
+    pub bench: fn(&[ContextWithCount], BenchConfig) -> Timing,
+    pub stream: fn(BackendKind) -> Option<Box<dyn measure::Stream>>,
+    #[cfg(test)]
+    pub check_round_trip: fn(&[ContextWithCount]),
+}
+
+macro_rules! strategy {
+    ($name:expr, $type:ty) => {
+        strategy!($name, $type, false)
+    };
+    ($name:expr, $type:ty, optional) => {
+        strategy!($name, $type, true)
+    };
+    ($name:expr, $type:ty, $optional:expr) => {
+        Strategy {
+            name: $name,
+            type_name: stringify!($type),
+            optional: $optional,
+            capacity: <$type as ContextSize>::CAPACITY,
+            holds: <$type as ContextSize>::holds,
+            measure: measure::measure::<$type>,
+            check_roundtrip: check_roundtrip::<$type>,
+            bench: bench::bench::<$type>,
+            stream: measure::stream::<$type>,
+            #[cfg(test)]
+            check_round_trip: synthetic::check_round_trip::<$type>,
         }
-        let size = t.get_pointer_size(context.count as usize);
-        total_size += size;
-    }
-    total_size += t.get_storage_size();
+    };
+}
 
-    total_size
+fn strategies() -> Vec<Strategy> {
+    vec![
+        strategy!("initial", initial_context::Context),
+        strategy!("compact", compact_temp_mapping::Context),
+        strategy!("rc-initial", deduplicated::ContextStorage<Rc<initial_context::Context>>),
+        strategy!("trie", hash_cons_2::ContextTrie),
+        strategy!("packed", packed_context::PackedContext),
+        strategy!("hash-cons", hash_cons::ContextHash),
+        strategy!("rc-compact", deduplicated::ContextStorage<Rc<compact_temp_mapping::Context>>),
+        strategy!("rc-packed", deduplicated::ContextStorage<Rc<packed_context::PackedContext>>),
+        strategy!("rclite-packed", deduplicated::ContextStorage<RcLite<packed_context::PackedContext>>),
+        strategy!("rclite-compact", deduplicated::ContextStorage<RcLite<compact_temp_mapping::Context>>),
+        strategy!("raw-compact", deduplicated::RawPointerStorage<compact_temp_mapping::Context>),
+        strategy!("bit-packed", bit_packed::BitPackedStorage),
+        strategy!("huffman", huffman::HuffmanStorage),
+        strategy!("arena-compact", arena::ArenaStorage<compact_temp_mapping::Context>),
+        strategy!("arena-packed", arena::ArenaStorage<packed_context::PackedContext>),
+        strategy!("arena16-compact", arena::ArenaStorage<compact_temp_mapping::Context, u16>, optional),
+        strategy!("parent-delta", parent_delta::ParentDeltaStorage),
+        // The upstream layout only goes through the strategies that hold a
+        // Context value. The packed, bit-packed, huffman, hash-cons, trie and
+        // parent-delta encodings take the fields apart themselves, and the
+        // fields upstream adds are always 0 in datasets, so an upstream
+        // version of them would measure the same as the one above.
+        strategy!("upstream", upstream_context::Context),
+        strategy!("rc-upstream", deduplicated::ContextStorage<Rc<upstream_context::Context>>),
+        strategy!("rclite-upstream", deduplicated::ContextStorage<RcLite<upstream_context::Context>>),
+        strategy!("raw-upstream", deduplicated::RawPointerStorage<upstream_context::Context>),
+        strategy!("arena-upstream", arena::ArenaStorage<upstream_context::Context>),
+        strategy!("arena16-upstream", arena::ArenaStorage<upstream_context::Context, u16>, optional),
+    ]
 }
 
+fn select_strategies(names: &[String]) -> Result<Vec<Strategy>, String> {
+    let mut all = strategies();
+    if names.is_empty() {
+        return Ok(all.into_iter().filter(|strategy| !strategy.optional).collect());
+    }
+    let mut selected = vec![];
+    for name in names {
+        match all.iter().position(|strategy| strategy.name == name) {
+            Some(index) => selected.push(all.remove(index)),
+            None if selected.iter().any(|strategy: &Strategy| strategy.name == name) => {}
+            None => return Err(format!("unknown strategy `{}` (see `context-size list`)", name)),
+        }
+    }
+    Ok(selected)
+}
+
+type Records = Box<dyn Iterator<Item = Result<ContextWithCount, String>>>;
+
+// Read the records of a binary dataset, a JSON lines dump or a YJIT log with
+// Debug formatted contexts. The first two are read as they're iterated, a log
+// is parsed whole to count identical contexts. Every strategy built on
+// packed_context relies on the contexts being packable, so reject the ones
+// that aren't here with their location
+fn dataset_records(path: &str) -> Result<Records, String> {
+    let mut prefix = vec![0; debug_dump::PREFIX_LEN];
+    let read = File::open(path)
+        .and_then(|mut file| file.read(&mut prefix))
+        .map_err(|e| format!("{}: {}", path, e))?;
 
-macro_rules! print_size {
-    ($type:ty, $contexts:expr) => {
-        println!("Total size {0: <80} {1: <10}", stringify!($type), total_size::<$type>(&$contexts))
+    let records: Records = if binary_dataset::is_binary_dataset(&prefix[..read]) {
+        Box::new(binary_dataset::read_dataset(path).map_err(|e| format!("{}: {}", path, e))?)
+    } else if debug_dump::is_debug_dump(&prefix[..read]) {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let contexts = debug_dump::parse_dump(&text).map_err(|e| format!("{}: {}", path, e))?;
+        Box::new(contexts.into_iter().map(Ok))
+    } else {
+        // Blank lines hold no record, so a blank file is an empty dataset
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let lines = BufReader::new(file)
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()));
+        Box::new(lines.map(|line| serde_json::from_str(&line.map_err(|e| e.to_string())?).map_err(|e| e.to_string())))
     };
+
+    let path = path.to_string();
+    Ok(Box::new(records.enumerate().map(move |(i, record)| {
+        let context = record.map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+        packed_context::pack_context(&context.context).map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+        Ok(context)
+    })))
+}
+
+fn load_dataset(path: &str) -> Result<Vec<ContextWithCount>, String> {
+    dataset_records(path)?.collect()
+}
+
+// Fail up front when the dataset has more unique contexts than a selected
+// strategy can hold, rather than part way through measuring it
+fn check_capacity(path: &str, strategies: &[Strategy], contexts: &[ContextWithCount]) -> Result<(), String> {
+    let unique = DatasetCounts::new(contexts).unique_contexts;
+    match strategies.iter().find(|strategy| strategy.capacity < unique) {
+        Some(strategy) => Err(format!(
+            "{}: {} unique contexts, but {} holds at most {}",
+            path, unique, strategy.name, strategy.capacity
+        )),
+        None => Ok(()),
+    }
+}
+
+// load_dataset for commands that store the contexts in every strategy
+fn load_dataset_for(path: &str, strategies: &[Strategy]) -> Result<Vec<ContextWithCount>, String> {
+    let contexts = load_dataset(path)?;
+    check_capacity(path, strategies, &contexts)?;
+    Ok(contexts)
+}
+
+// The contexts every one of the strategies holds, saying how many each of them
+// leaves out so a lossy strategy isn't measured on a collapsed dataset
+fn held_contexts<'a>(path: &str, strategies: &[Strategy], contexts: &'a [ContextWithCount]) -> Cow<'a, [ContextWithCount]> {
+    let mut held = Cow::Borrowed(contexts);
+    for strategy in strategies.iter() {
+        let left_out = held.iter().filter(|context| !(strategy.holds)(&context.context)).count();
+        if left_out > 0 {
+            eprintln!("{}: {}: left out {} contexts it can't hold", path, strategy.name, left_out);
+            held = Cow::Owned(held.iter().filter(|context| (strategy.holds)(&context.context)).cloned().collect());
+        }
+    }
+    held
+}
+
+// Records a streamed strategy can't hold, with the hashes of the unique ones
+#[derive(Default)]
+struct LeftOut {
+    records: usize,
+    total_contexts: u64,
+    unique: HashSet<u64>,
+}
+
+// Measure every strategy in one pass over the dataset without keeping the
+// records around. Strategies that have to be trained on the whole dataset are
+// left out.
+fn stream_sizes(path: &str, strategies: &[Strategy], backend: BackendKind) -> Result<Vec<SizeResult>, String> {
+    let mut streams = vec![];
+    for strategy in strategies.iter() {
+        match (strategy.stream)(backend) {
+            Some(stream) => streams.push((strategy, stream, LeftOut::default())),
+            None => eprintln!("{}: {}: can't be streamed, skipping", path, strategy.name),
+        }
+    }
+
+    // Hashes stand in for the contexts to count the unique ones in less memory
+    let mut unique = HashSet::new();
+    let mut counts = DatasetCounts::default();
+    for record in dataset_records(path)? {
+        let record = record?;
+        let mut hasher = DefaultHasher::new();
+        record.context.hash(&mut hasher);
+        let hash = hasher.finish();
+        if unique.insert(hash) {
+            if let Some((strategy, ..)) = streams.iter().find(|(strategy, ..)| strategy.capacity < unique.len()) {
+                return Err(format!("{}: more unique contexts than {} holds ({})", path, strategy.name, strategy.capacity));
+            }
+        }
+        for (strategy, stream, left_out) in streams.iter_mut() {
+            if (strategy.holds)(&record.context) {
+                stream.feed(&record);
+            } else {
+                left_out.records += 1;
+                left_out.total_contexts += record.count;
+                left_out.unique.insert(hash);
+            }
+        }
+        counts.total_contexts += record.count;
+    }
+    counts.unique_contexts = unique.len();
+    for (strategy, _, left_out) in streams.iter().filter(|(.., left_out)| left_out.records > 0) {
+        eprintln!("{}: {}: left out {} contexts it can't hold", path, strategy.name, left_out.records);
+    }
+
+    Ok(streams
+        .iter()
+        .map(|(strategy, stream, left_out)| {
+            let held = DatasetCounts {
+                unique_contexts: counts.unique_contexts - left_out.unique.len(),
+                total_contexts: counts.total_contexts - left_out.total_contexts,
+            };
+            SizeResult::new(path, strategy, backend.name(), held, stream.finish())
+        })
+        .collect())
+}
+
+// Pack and unpack every context, returning how many didn't come back unchanged
+fn roundtrip_packing(path: &str, contexts: &[ContextWithCount]) -> usize {
+    let mut mismatches = 0;
+    for (i, context) in contexts.iter().enumerate() {
+        let packed = packed_context::pack_context(&context.context).unwrap();
+        let unpacked = packed_context::unpack_context(&packed);
+        if unpacked != context.context {
+            mismatches += 1;
+            println!("{}:{}: mismatch", path, i + 1);
+            println!("  original: {:?}", context.context);
+            println!("  packed:   {:?}", packed);
+            println!("  unpacked: {:?}", unpacked);
+        }
+    }
+    println!("{}: pack_context: {} records, {} mismatches", path, contexts.len(), mismatches);
+    mismatches
+}
+
+// Run every strategy's store/load round trip. A panicking strategy counts as
+// one failure so the remaining strategies still get checked.
+fn roundtrip_strategies(path: &str, strategies: &[Strategy], contexts: &[ContextWithCount]) -> usize {
+    let mut failures = 0;
+    for strategy in strategies.iter() {
+        let contexts = held_contexts(path, slice::from_ref(strategy), contexts);
+        match panic::catch_unwind(|| (strategy.check_roundtrip)(&contexts)) {
+            Ok(mismatches) => {
+                println!("{}: {}: {} records, {} mismatches", path, strategy.name, contexts.len(), mismatches);
+                failures += mismatches;
+            }
+            Err(_) => {
+                println!("{}: {}: panicked", path, strategy.name);
+                failures += 1;
+            }
+        }
+    }
+    failures
+}
+
+// Measure every strategy with every backend and flag the ones they disagree on
+fn crosscheck(path: &str, strategies: &[Strategy], contexts: &[ContextWithCount]) -> usize {
+    println!("{}", path);
+    println!(
+        "{0: <16} {1: >12} {2: >12} {3: >12} {4: >12} {5: >12}",
+        "strategy", "deepsize", "allocator", "allocations", "peak", "analytic"
+    );
+    let mut flagged = 0;
+    for strategy in strategies.iter() {
+        let contexts = held_contexts(path, slice::from_ref(strategy), contexts);
+        let measurements = BackendKind::ALL.map(|backend| (strategy.measure)(backend, &contexts));
+        let [deep_size, allocator, analytic] = measurements;
+        let disagree = measure::disagree(&measurements);
+        if disagree {
+            flagged += 1;
+        }
+        println!(
+            "{0: <16} {1: >12} {2: >12} {3: >12} {4: >12} {5: >12} {6}",
+            strategy.name,
+            deep_size.bytes,
+            allocator.bytes,
+            allocator.allocations.unwrap_or(0),
+            allocator.peak_bytes.unwrap_or(0),
+            analytic.bytes,
+            if disagree { "DISAGREE" } else { "" },
+        );
+    }
+    flagged
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let strategies = select_strategies(&args.strategies)?;
+
+    if args.command == Command::List {
+        for strategy in strategies.iter() {
+            println!("{0: <16} {1}", strategy.name, strategy.type_name);
+        }
+        for strategy in crate::strategies().iter().filter(|strategy| args.strategies.is_empty() && strategy.optional) {
+            println!("{0: <16} {1} (only when selected)", strategy.name, strategy.type_name);
+        }
+        return Ok(());
+    }
+
+    if args.command == Command::Generate {
+        let path = &args.datasets[0];
+        let contexts = synthetic::Generator::new(args.generator).dataset();
+        serde_jsonlines::write_json_lines(path, &contexts).map_err(|e| format!("{}: {}", path, e))?;
+        println!("{}: {} contexts", path, contexts.len());
+        return Ok(());
+    }
+
+    if args.command == Command::Convert {
+        let (input, output) = (&args.datasets[0], &args.datasets[1]);
+        let contexts = load_dataset(input)?;
+        if output.ends_with(".json") || output.ends_with(".jsonl") {
+            serde_jsonlines::write_json_lines(output, &contexts)
+        } else {
+            binary_dataset::write_dataset(output, &contexts)
+        }
+        .map_err(|e| format!("{}: {}", output, e))?;
+        println!("{}: {} contexts", output, contexts.len());
+        return Ok(());
+    }
+
+    if args.command == Command::Compare {
+        let baseline = crate::strategies().into_iter().find(|strategy| strategy.name == "initial").unwrap();
+        let mut labels = vec![];
+        let mut baseline_bytes = vec![];
+        let mut sizes = vec![vec![]; strategies.len()];
+        for arg in args.datasets.iter() {
+            let (label, path) = compare::split_label(arg);
+            // Every column compares the same contexts
+            let contexts = load_dataset_for(&path, &strategies)?;
+            let contexts = held_contexts(&path, &strategies, &contexts);
+            baseline_bytes.push((baseline.measure)(args.backend, &contexts).bytes);
+            for (strategy, sizes) in strategies.iter().zip(sizes.iter_mut()) {
+                sizes.push((strategy.measure)(args.backend, &contexts).bytes);
+            }
+            labels.push(label);
+        }
+        let cells = compare::comparison(&labels, &baseline_bytes, &strategies, &sizes);
+        compare::print_comparison(args.format, &labels, &cells);
+        return Ok(());
+    }
+
+    if args.command == Command::Stats {
+        let mut stats = vec![];
+        for path in args.datasets.iter() {
+            stats.push(stats::dataset_stats(path, &load_dataset(path)?));
+        }
+        stats::print_stats(args.format, &stats);
+        return Ok(());
+    }
+
+    if args.command == Command::Sweep {
+        let mut results = vec![];
+        for path in args.datasets.iter() {
+            results.extend(sweep::sweep(path, &load_dataset(path)?));
+        }
+        sweep::print_sweep(args.format, &results);
+        return Ok(());
+    }
+
+    if args.command == Command::Roundtrip {
+        let mut mismatches = 0;
+        for path in args.datasets.iter() {
+            let contexts = load_dataset_for(path, &strategies)?;
+            mismatches += roundtrip_packing(path, &contexts);
+            mismatches += roundtrip_strategies(path, &strategies, &contexts);
+        }
+        if mismatches > 0 {
+            return Err(format!("{} contexts did not round-trip", mismatches));
+        }
+        return Ok(());
+    }
+
+    if args.command == Command::Crosscheck {
+        let mut flagged = 0;
+        for path in args.datasets.iter() {
+            flagged += crosscheck(path, &strategies, &load_dataset_for(path, &strategies)?);
+        }
+        println!(
+            "{} strategies where the backends differ by more than {}%",
+            flagged,
+            measure::TOLERANCE * 100.0
+        );
+        return Ok(());
+    }
+
+    if args.command == Command::Bench {
+        let mut results = vec![];
+        for path in args.datasets.iter() {
+            let contexts = load_dataset_for(path, &strategies)?;
+            for strategy in strategies.iter() {
+                let timing = (strategy.bench)(&held_contexts(path, slice::from_ref(strategy), &contexts), args.bench);
+                results.push(BenchResult::new(path, strategy, timing));
+            }
+        }
+        report::print_bench_results(args.format, &results);
+        return Ok(());
+    }
+
+    if args.stream {
+        if args.backend == BackendKind::Allocator {
+            return Err("the allocator backend can't be streamed".to_string());
+        }
+        let mut results = vec![];
+        for path in args.datasets.iter() {
+            results.extend(stream_sizes(path, &strategies, args.backend)?);
+        }
+        report::print_results(args.format, &results);
+        return Ok(());
+    }
+
+    let mut results = vec![];
+    let mut entropy = vec![];
+    for path in args.datasets.iter() {
+        let contexts = load_dataset_for(path, &strategies)?;
+        let mut dataset_results = vec![];
+        for strategy in strategies.iter() {
+            // Counts of what the strategy stored, which is less than the
+            // dataset when it can't hold some of the contexts
+            let held = held_contexts(path, slice::from_ref(strategy), &contexts);
+            let measurement = (strategy.measure)(args.backend, &held);
+            dataset_results.push(SizeResult::new(path, strategy, args.backend.name(), DatasetCounts::new(&held), measurement));
+        }
+        if args.command == Command::Entropy {
+            entropy.push(entropy::entropy_report(path, &contexts, &dataset_results));
+        }
+        results.extend(dataset_results);
+    }
+    if args.command == Command::Entropy {
+        entropy::print_entropy(args.format, &entropy);
+    } else {
+        report::print_results(args.format, &results);
+    }
+    Ok(())
 }
 
 fn main() {
-    let path = "/Users/jimmyhmiller/Downloads/railsbench_ctx_duplications.json";
-
-    let contexts: Vec<ContextWithCount> = serde_jsonlines::json_lines(path)
-        .unwrap()
-        .map(|x| x.unwrap())
-        .collect_vec();
-
-    print_size!(initial_context::Context, &contexts);
-    print_size!(compact_temp_mapping::Context, &contexts);
-    print_size!(deduplicated::ContextStorage<Rc<initial_context::Context>>, &contexts);
-    print_size!(hash_cons_2::ContextNode, &contexts);
-    print_size!(packed_context::PackedContext, &contexts);
-    print_size!(hash_cons::ContextHash, &contexts);
-    print_size!(deduplicated::ContextStorage<Rc<compact_temp_mapping::Context>>, &contexts);
-    print_size!(deduplicated::ContextStorage<Rc<packed_context::PackedContext>>, &contexts);
-    print_size!(deduplicated::ContextStorage<RcLite<packed_context::PackedContext>>, &contexts);
-    print_size!(deduplicated::ContextStorage<RcLite<compact_temp_mapping::Context>>, &contexts);
-    print_size!(deduplicated::ContextStorage<RawPointer<compact_temp_mapping::Context>>, &contexts);
+    let args = match cli::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err @ CliError::Help) => {
+            print!("{}", err);
+            return;
+        }
+        Err(err) => {
+            eprint!("{}", err);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = run(args) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::{test_contexts, test_records, Generator, GeneratorConfig};
+
+    // Every strategy loads back every context it holds, and its handles are
+    // equal exactly when the contexts are. Generated successors give
+    // parent-delta parents to store against.
+    #[test]
+    fn every_strategy_round_trips() {
+        let config = GeneratorConfig { seed: 15, contexts: 1500, successor: 0.5, ..Default::default() };
+        let mut records = Generator::new(config).dataset();
+        records.extend(test_records(&test_contexts(15, 1500)));
+
+        for strategy in strategies().iter() {
+            // Shown when a check fails
+            println!("{}", strategy.name);
+            let held = held_contexts("test", slice::from_ref(strategy), &records);
+            assert!(held.len() > records.len() / 2, "{} holds {} contexts", strategy.name, held.len());
+            (strategy.check_round_trip)(&held);
+        }
+    }
 }
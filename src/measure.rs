@@ -0,0 +1,350 @@
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    mem::size_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use stats_alloc::{Region, INSTRUMENTED_SYSTEM};
+
+use crate::{initial_context::{self, ContextWithCount}, new_strategy, ContextSize};
+
+// Backends whose byte counts differ by more than this fraction get flagged
+pub const TOLERANCE: f64 = 0.10;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Measurement {
+    pub bytes: usize,
+    // Split of bytes between the handles held for every occurrence and the
+    // storage they share
+    pub pointer_bytes: usize,
+    pub storage_bytes: usize,
+    // Live heap blocks, only known when counting real allocations
+    pub allocations: Option<usize>,
+    // Highest heap usage while storing the contexts
+    pub peak_bytes: Option<usize>,
+}
+
+// A way of working out how much memory a strategy needs for a dataset
+pub trait Backend {
+    const NAME: &'static str;
+
+    fn measure<T>(contexts: &[ContextWithCount]) -> Measurement
+    where
+        T: ContextSize + Default,
+        <T as ContextSize>::Context: From<initial_context::Context>;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    DeepSize,
+    Allocator,
+    Analytic,
+}
+
+impl BackendKind {
+    pub const ALL: [BackendKind; 3] = [BackendKind::DeepSize, BackendKind::Allocator, BackendKind::Analytic];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BackendKind::DeepSize => DeepSize::NAME,
+            BackendKind::Allocator => Allocator::NAME,
+            BackendKind::Analytic => Analytic::NAME,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BackendKind> {
+        BackendKind::ALL.into_iter().find(|backend| backend.name() == name)
+    }
+}
+
+pub fn measure<T>(backend: BackendKind, contexts: &[ContextWithCount]) -> Measurement
+where
+    T: ContextSize + Default,
+    <T as ContextSize>::Context: From<initial_context::Context>,
+{
+    match backend {
+        BackendKind::DeepSize => DeepSize::measure::<T>(contexts),
+        BackendKind::Allocator => Allocator::measure::<T>(contexts),
+        BackendKind::Analytic => Analytic::measure::<T>(contexts),
+    }
+}
+
+// Whether the byte counts are further apart than TOLERANCE
+pub fn disagree(measurements: &[Measurement]) -> bool {
+    let max = measurements.iter().map(|m| m.bytes).max().unwrap_or(0);
+    let min = measurements.iter().map(|m| m.bytes).min().unwrap_or(0);
+    max > 0 && (max - min) as f64 / max as f64 > TOLERANCE
+}
+
+
+// Walks the stored values with deepsize::DeepSizeOf
+pub struct DeepSize;
+
+impl Backend for DeepSize {
+    const NAME: &'static str = "deepsize";
+
+    fn measure<T>(contexts: &[ContextWithCount]) -> Measurement
+    where
+        T: ContextSize + Default,
+        <T as ContextSize>::Context: From<initial_context::Context>,
+    {
+        let mut accumulator = Accumulator::new(new_strategy::<T>(contexts), BackendKind::DeepSize);
+        for context in contexts.iter() {
+            accumulator.feed(context);
+        }
+        accumulator.finish()
+    }
+}
+
+
+// Counts the heap memory that is still allocated after storing every context,
+// keeping one handle per occurrence in a Vec so inline handles are counted too.
+// Whatever dropping the handles frees is counted as pointer bytes.
+pub struct Allocator;
+
+impl Backend for Allocator {
+    const NAME: &'static str = "allocator";
+
+    fn measure<T>(contexts: &[ContextWithCount]) -> Measurement
+    where
+        T: ContextSize + Default,
+        <T as ContextSize>::Context: From<initial_context::Context>,
+    {
+        let handles: usize = contexts.iter().map(|context| context.count as usize).sum();
+
+        let base = GLOBAL.reset_peak();
+        let region = Region::new(&INSTRUMENTED_SYSTEM);
+
+        let mut t: T = new_strategy(contexts);
+        let mut pointers = Vec::with_capacity(handles);
+        for context in contexts.iter() {
+            let new_context: T::Context = context.context.clone().into();
+            for _ in 0..context.count {
+                pointers.push(t.store_context(new_context.clone()));
+            }
+        }
+
+        let change = region.change();
+        let peak = GLOBAL.peak().saturating_sub(base);
+        let bytes = change.bytes_allocated.saturating_sub(change.bytes_deallocated);
+
+        let region = Region::new(&INSTRUMENTED_SYSTEM);
+        drop(pointers);
+        let freed = region.change();
+        let pointer_bytes = freed.bytes_deallocated.saturating_sub(freed.bytes_allocated).min(bytes);
+        drop(t);
+
+        Measurement {
+            bytes,
+            pointer_bytes,
+            storage_bytes: bytes - pointer_bytes,
+            allocations: Some(change.allocations.saturating_sub(change.deallocations)),
+            peak_bytes: Some(peak),
+        }
+    }
+}
+
+
+// Works the size out from the layout of the types through
+// ContextSize::get_analytic_pointer_size and get_analytic_storage_size
+pub struct Analytic;
+
+impl Backend for Analytic {
+    const NAME: &'static str = "analytic";
+
+    fn measure<T>(contexts: &[ContextWithCount]) -> Measurement
+    where
+        T: ContextSize + Default,
+        <T as ContextSize>::Context: From<initial_context::Context>,
+    {
+        let mut accumulator = Accumulator::new(new_strategy::<T>(contexts), BackendKind::Analytic);
+        for context in contexts.iter() {
+            accumulator.feed(context);
+        }
+        accumulator.finish()
+    }
+}
+
+
+// Stores records in a strategy one at a time, adding up the pointer bytes as
+// it goes, for the backends that don't need every handle kept alive
+pub struct Accumulator<T> {
+    t: T,
+    backend: BackendKind,
+    pointer_bytes: usize,
+}
+
+// An Accumulator with the strategy type erased, so every selected strategy
+// can be fed from the same pass over a dataset
+pub trait Stream {
+    fn feed(&mut self, context: &ContextWithCount);
+    fn finish(&self) -> Measurement;
+}
+
+impl<T> Accumulator<T>
+where
+    T: ContextSize,
+    <T as ContextSize>::Context: From<initial_context::Context>,
+{
+    pub fn new(t: T, backend: BackendKind) -> Self {
+        assert!(backend != BackendKind::Allocator, "the allocator backend keeps every handle");
+        Accumulator { t, backend, pointer_bytes: 0 }
+    }
+}
+
+impl<T> Stream for Accumulator<T>
+where
+    T: ContextSize,
+    <T as ContextSize>::Context: From<initial_context::Context>,
+{
+    fn feed(&mut self, context: &ContextWithCount) {
+        let new_context: T::Context = context.context.clone().into();
+        let mut pointer = None;
+        for _ in 0..context.count {
+            pointer = Some(self.t.store_context(new_context.clone()));
+        }
+        if let Some(pointer) = pointer {
+            self.pointer_bytes += match self.backend {
+                BackendKind::Analytic => self.t.get_analytic_pointer_size(&pointer) * context.count as usize,
+                _ => self.t.get_pointer_size(&pointer, context.count as usize),
+            };
+        }
+    }
+
+    fn finish(&self) -> Measurement {
+        let storage_bytes = match self.backend {
+            BackendKind::Analytic => self.t.get_analytic_storage_size(),
+            _ => self.t.get_storage_size(),
+        };
+        Measurement {
+            bytes: self.pointer_bytes + storage_bytes,
+            pointer_bytes: self.pointer_bytes,
+            storage_bytes,
+            ..Measurement::default()
+        }
+    }
+}
+
+// A Stream for the strategy, unless it has to be trained on the whole dataset
+// first or the backend needs every handle
+pub fn stream<T>(backend: BackendKind) -> Option<Box<dyn Stream>>
+where
+    T: ContextSize + Default + 'static,
+    <T as ContextSize>::Context: From<initial_context::Context>,
+{
+    if T::NEEDS_TRAINING || backend == BackendKind::Allocator {
+        return None;
+    }
+    Some(Box::new(Accumulator::new(T::default(), backend)))
+}
+
+
+// Layout of the allocation behind a std::rc::Rc
+#[allow(dead_code)]
+#[repr(C)]
+struct RcBox<T> {
+    _strong: usize,
+    _weak: usize,
+    _value: T,
+}
+
+// Layout of the allocation behind an rclite::Rc on 64-bit targets
+#[allow(dead_code)]
+#[repr(C)]
+struct RcLiteInner<T> {
+    _value: T,
+    _counter: u32,
+}
+
+pub fn rc_box_size<T>() -> usize {
+    size_of::<RcBox<T>>()
+}
+
+pub fn rclite_inner_size<T>() -> usize {
+    size_of::<RcLiteInner<T>>()
+}
+
+// Bytes of the table behind a std HashSet/HashMap with the given capacity:
+// one slot and one control byte per bucket, plus a trailing group of control bytes
+pub fn hash_table_size<T>(capacity: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+    let buckets = if capacity < 7 { capacity + 1 } else { capacity / 7 * 8 }.next_power_of_two();
+    const GROUP_WIDTH: usize = 16;
+    buckets * size_of::<T>() + buckets + GROUP_WIDTH
+}
+
+
+// Wraps the stats_alloc allocator to also keep track of the peak heap usage
+pub struct PeakAlloc {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl PeakAlloc {
+    const fn new() -> Self {
+        PeakAlloc {
+            current: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    // Start tracking a new peak, returning the current usage
+    pub fn reset_peak(&self) -> usize {
+        let current = self.current.load(Ordering::SeqCst);
+        self.peak.store(current, Ordering::SeqCst);
+        current
+    }
+
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::SeqCst)
+    }
+
+    fn grow(&self, bytes: usize) {
+        let current = self.current.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        self.peak.fetch_max(current, Ordering::SeqCst);
+    }
+
+    fn shrink(&self, bytes: usize) {
+        self.current.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = INSTRUMENTED_SYSTEM.alloc(layout);
+        if !ptr.is_null() {
+            self.grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.shrink(layout.size());
+        INSTRUMENTED_SYSTEM.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = INSTRUMENTED_SYSTEM.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            self.grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = INSTRUMENTED_SYSTEM.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            if new_size > layout.size() {
+                self.grow(new_size - layout.size());
+            } else {
+                self.shrink(layout.size() - new_size);
+            }
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static GLOBAL: PeakAlloc = PeakAlloc::new();
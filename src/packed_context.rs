@@ -1,21 +1,23 @@
+use std::{fmt, mem::size_of};
+
 use deepsize::DeepSizeOf;
 use serde::{Serialize, Deserialize};
 
-use crate::{initial_context::{Type, Context}, ContextSize, compact_temp_mapping::{TempMapping, self}};
+use crate::{initial_context::{Type, Context}, ContextSize, compact_temp_mapping::{tracked_mapping, TempMapping, self}};
 
 // Compressed version of Context
 pub type PackedContext = Box<[ContextDelta]>;
 
 // The fields of each variant should only use 2 bytes, which makes this enum 3 bytes.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, DeepSizeOf)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, DeepSizeOf)]
 pub enum ContextDelta {
     None,
     // stack_size, sp_offset: Small case
     SmallStack(u8, i8),
-    // stack_size: Large case
-    //StackSize(u8, u8), // TODO: implement this (using u16 here takes more than 2 bytes)
-    // sp_offset: Large case
-    //SpOffset(i8, i8),  // TODO: implement this (using i16 here takes more than 2 bytes)
+    // stack_size: Large case, little-endian bytes (using u16 here takes more than 2 bytes)
+    StackSize([u8; 2]),
+    // sp_offset: Large case, little-endian bytes (using i16 here takes more than 2 bytes)
+    SpOffset([u8; 2]),
     // chain_depth
     ChainDepth(u8),
     // local_types
@@ -24,86 +26,136 @@ pub enum ContextDelta {
     TempType(u8, Type),
     // self_type
     SelfType(Type),
-    // temp_mapping: Not using (u8, TempMapping) to save 1 byte.
-    TempMapping0(TempMapping),
-    TempMapping1(TempMapping),
-    TempMapping2(TempMapping),
-    TempMapping3(TempMapping),
-    TempMapping4(TempMapping),
-    TempMapping5(TempMapping),
-    TempMapping6(TempMapping),
-    TempMapping7(TempMapping),
+    // temp_mapping
+    TempMapping(u8, TempMapping),
+}
+
+const _: () = assert!(size_of::<ContextDelta>() == 3);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PackError {
+    // A temp maps to a local we don't track the type of
+    LocalIndex { index: u8, tracked: usize },
+}
+
+impl fmt::Display for PackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PackError::LocalIndex { index, tracked } => write!(
+                f,
+                "temp maps to local {} but only {} locals are tracked",
+                index, tracked
+            ),
+        }
+    }
 }
 
 // Deflate Context
-pub fn pack_context(ctx: &Context) -> PackedContext {
+pub fn pack_context<const LOCALS: usize, const TEMPS: usize>(ctx: &Context<LOCALS, TEMPS>) -> Result<PackedContext, PackError> {
+    diff_context(&Context::default(), ctx)
+}
 
-    let ctx : compact_temp_mapping::Context = ctx.clone().into();
+// The deltas that turn base into ctx, for every field where they differ
+pub fn diff_context<const LOCALS: usize, const TEMPS: usize>(
+    base: &Context<LOCALS, TEMPS>,
+    ctx: &Context<LOCALS, TEMPS>,
+) -> Result<PackedContext, PackError> {
     let mut packed = vec![];
+    let track = |mapping| tracked_mapping::<LOCALS>(mapping).map_err(|index| PackError::LocalIndex { index, tracked: LOCALS });
 
-    if ctx.self_type != Type::Unknown {
+    if ctx.self_type != base.self_type {
         packed.push(ContextDelta::SelfType(ctx.self_type));
     }
 
 
-    if ctx.chain_depth > 0 {
+    if ctx.chain_depth != base.chain_depth {
         packed.push(ContextDelta::ChainDepth(ctx.chain_depth));
     }
 
-    for (i, &local_type) in ctx.local_types.iter().enumerate() {
-        if local_type != Type::Unknown {
+    for (i, (&local_type, &base_type)) in ctx.local_types.iter().zip(base.local_types.iter()).enumerate() {
+        if local_type != base_type {
             packed.push(ContextDelta::LocalType(i.try_into().unwrap(), local_type));
         }
     }
 
-    for (i, &temp_type) in ctx.temp_types.iter().enumerate() {
-        if temp_type != Type::Unknown {
+    for (i, (&temp_type, &base_type)) in ctx.temp_types.iter().zip(base.temp_types.iter()).enumerate() {
+        if temp_type != base_type {
             packed.push(ContextDelta::TempType(i.try_into().unwrap(), temp_type));
         }
     }
 
 
-    for (i, &temp_mapping) in ctx.temp_mapping.iter().enumerate() {
-        if temp_mapping != TempMapping::MapToStack {
-            match i {
-                0 => packed.push(ContextDelta::TempMapping0(temp_mapping)),
-                1 => packed.push(ContextDelta::TempMapping1(temp_mapping)),
-                2 => packed.push(ContextDelta::TempMapping2(temp_mapping)),
-                3 => packed.push(ContextDelta::TempMapping3(temp_mapping)),
-                4 => packed.push(ContextDelta::TempMapping4(temp_mapping)),
-                5 => packed.push(ContextDelta::TempMapping5(temp_mapping)),
-                6 => packed.push(ContextDelta::TempMapping6(temp_mapping)),
-                7 => packed.push(ContextDelta::TempMapping7(temp_mapping)),
-                _ => unreachable!(),
-            }
+    for (i, (&temp_mapping, &base_mapping)) in ctx.temp_mapping.iter().zip(base.temp_mapping.iter()).enumerate() {
+        let temp_mapping = track(temp_mapping)?;
+        if temp_mapping != track(base_mapping)? {
+            packed.push(ContextDelta::TempMapping(i.try_into().unwrap(), temp_mapping));
         }
     }
 
-    if ctx.stack_size != 0 || ctx.sp_offset != 0 {
+    if ctx.stack_size != base.stack_size || ctx.sp_offset != base.sp_offset {
         match (u8::try_from(ctx.stack_size), i8::try_from(ctx.sp_offset)) {
             (Ok(stack_size), Ok(sp_offset)) => packed.push(
                 ContextDelta::SmallStack(stack_size, sp_offset)
             ),
             _ => {
-                unreachable!("not implemented yet")
-                //if ctx.stack_size != 0 {
-                //    packed.push(ContextDelta::StackSize(ctx.stack_size));
-                //}
-                //if ctx.sp_offset != 0 {
-                //    packed.push(ContextDelta::SpOffset(ctx.sp_offset));
-                //}
+                if ctx.stack_size != base.stack_size {
+                    packed.push(ContextDelta::StackSize(ctx.stack_size.to_le_bytes()));
+                }
+                if ctx.sp_offset != base.sp_offset {
+                    packed.push(ContextDelta::SpOffset(ctx.sp_offset.to_le_bytes()));
+                }
             },
         }
     }
 
 
-    packed.into_boxed_slice()
+    Ok(packed.into_boxed_slice())
+}
+
+fn apply<const LOCALS: usize, const TEMPS: usize>(
+    mut ctx: compact_temp_mapping::Context<LOCALS, TEMPS>,
+    packed: &[ContextDelta],
+) -> compact_temp_mapping::Context<LOCALS, TEMPS> {
+    for &delta in packed.iter() {
+        match delta {
+            ContextDelta::None => {}
+            ContextDelta::SmallStack(stack_size, sp_offset) => {
+                ctx.stack_size = stack_size.into();
+                ctx.sp_offset = sp_offset.into();
+            }
+            ContextDelta::StackSize(bytes) => ctx.stack_size = u16::from_le_bytes(bytes),
+            ContextDelta::SpOffset(bytes) => ctx.sp_offset = i16::from_le_bytes(bytes),
+            ContextDelta::ChainDepth(chain_depth) => ctx.chain_depth = chain_depth,
+            ContextDelta::LocalType(i, local_type) => ctx.local_types[i as usize] = local_type,
+            ContextDelta::TempType(i, temp_type) => ctx.temp_types[i as usize] = temp_type,
+            ContextDelta::SelfType(self_type) => ctx.self_type = self_type,
+            ContextDelta::TempMapping(i, temp_mapping) => ctx.temp_mapping[i as usize] = temp_mapping,
+        }
+    }
+    ctx
+}
+
+// Inflate Context, restoring the default for every field pack_context elided
+pub fn unpack_context<const LOCALS: usize, const TEMPS: usize>(packed: &[ContextDelta]) -> Context<LOCALS, TEMPS> {
+    apply(compact_temp_mapping::Context::default(), packed).into()
+}
+
+// Undo diff_context against the same base
+pub fn apply_deltas<const LOCALS: usize, const TEMPS: usize>(base: &Context<LOCALS, TEMPS>, packed: &[ContextDelta]) -> Context<LOCALS, TEMPS> {
+    apply(base.clone().into(), packed).into()
 }
 
 
-impl From<Context> for PackedContext {
-    fn from(ctx: Context) -> Self {
-        pack_context(&ctx)
+impl<const LOCALS: usize, const TEMPS: usize> From<Context<LOCALS, TEMPS>> for PackedContext {
+    fn from(ctx: Context<LOCALS, TEMPS>) -> Self {
+        // Datasets are checked with pack_context when they're loaded
+        pack_context(&ctx).expect("context can't be packed")
+    }
+}
+
+impl<const LOCALS: usize, const TEMPS: usize> From<PackedContext> for Context<LOCALS, TEMPS> {
+    fn from(packed: PackedContext) -> Self {
+        unpack_context(&packed)
     }
 }
 
@@ -112,11 +164,73 @@ impl ContextSize for PackedContext {
     type Pointer = PackedContext;
     type Storage = ();
 
-    fn get_pointer(&self) -> Self::Pointer {
-        self.clone()
-    }
-
     fn get_storage(&self) -> Option<Self::Storage> {
         None
     }
+
+    fn store_context(&mut self, context: Self::Context) -> Self::Pointer {
+        context
+    }
+
+    fn load_context(&self, pointer: &Self::Pointer) -> Context {
+        unpack_context(pointer)
+    }
+
+    fn get_analytic_pointer_size(&self, pointer: &Self::Pointer) -> usize {
+        size_of::<PackedContext>() + pointer.len() * size_of::<ContextDelta>()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{initial_context::{self, MAX_LOCAL_TYPES}, synthetic::{assert_equal_handles, test_contexts}};
+
+    #[test]
+    fn unpacks_to_the_same_context() {
+        let contexts = test_contexts(2, 1000);
+        let packed: Vec<PackedContext> = contexts.iter().map(|ctx| pack_context(ctx).unwrap()).collect();
+        for (ctx, packed) in contexts.iter().zip(packed.iter()) {
+            assert_eq!(unpack_context(packed), *ctx);
+        }
+        assert_equal_handles(&contexts, &packed);
+        assert!(pack_context(&<Context>::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn diffs_apply_to_their_base() {
+        let contexts = test_contexts(3, 500);
+        for (base, ctx) in contexts.iter().zip(contexts.iter().rev()) {
+            let deltas = diff_context(base, ctx).unwrap();
+            assert_eq!(apply_deltas(base, &deltas), *ctx);
+            assert_eq!(deltas.is_empty(), base == ctx);
+        }
+    }
+
+    #[test]
+    fn other_limits_unpack_to_the_same_context() {
+        let contexts: Vec<Context<16, 4>> = test_contexts(6, 500).iter().map(Context::resize).collect();
+        let packed: Vec<PackedContext> = contexts.iter().map(|ctx| pack_context(ctx).unwrap()).collect();
+        for (ctx, packed) in contexts.iter().zip(packed.iter()) {
+            assert_eq!(unpack_context::<16, 4>(packed), *ctx);
+            assert_eq!(compact_temp_mapping::Context::from(ctx.clone()), apply(Default::default(), packed));
+        }
+
+        // Going back to the default limits only loses what wasn't tracked
+        for (ctx, wide) in test_contexts(6, 500).iter().zip(contexts.iter()) {
+            let narrow: Context<MAX_LOCAL_TYPES, 4> = ctx.resize();
+            assert_eq!(wide.resize::<MAX_LOCAL_TYPES, 4>(), narrow);
+        }
+    }
+
+    #[test]
+    fn untracked_locals_fail_to_pack() {
+        let mut ctx: Context = Context::default();
+        ctx.temp_mapping[3] = initial_context::TempMapping::MapToLocal(MAX_LOCAL_TYPES as u8);
+        assert_eq!(
+            pack_context(&ctx),
+            Err(PackError::LocalIndex { index: MAX_LOCAL_TYPES as u8, tracked: MAX_LOCAL_TYPES })
+        );
+    }
 }
@@ -0,0 +1,197 @@
+use std::{collections::HashMap, mem::size_of};
+
+use deepsize::DeepSizeOf;
+
+use crate::{
+    initial_context::{Context, ContextWithCount},
+    measure::hash_table_size,
+    packed_context::{apply_deltas, diff_context, ContextDelta, PackedContext},
+    ContextSize,
+};
+
+// The context of the parent block of every context that has one in the
+// dataset, taking the first parent seen. Edges that would make a context its
+// own ancestor are left out, so following parents always ends.
+pub fn parent_contexts(contexts: &[ContextWithCount]) -> HashMap<Context, Context> {
+    let blocks: HashMap<u64, &Context> = contexts
+        .iter()
+        .filter_map(|context| context.block.map(|block| (block, &context.context)))
+        .collect();
+
+    let mut parents: HashMap<Context, Context> = HashMap::new();
+    for context in contexts.iter() {
+        let Some(parent) = context.parent.and_then(|parent| blocks.get(&parent)) else {
+            continue;
+        };
+        if parents.contains_key(&context.context) {
+            continue;
+        }
+        let mut ancestor = Some(*parent);
+        while let Some(current) = ancestor {
+            if *current == context.context {
+                break;
+            }
+            ancestor = parents.get(current);
+        }
+        if ancestor.is_none() {
+            parents.insert(context.context.clone(), (*parent).clone());
+        }
+    }
+    parents
+}
+
+// A context as the deltas from its parent's node, or from the default context
+// when it has no parent
+#[derive(Clone, Debug, PartialEq, Eq, Hash, DeepSizeOf)]
+pub struct DeltaNode {
+    parent: Option<u32>,
+    deltas: PackedContext,
+}
+
+impl DeltaNode {
+    fn new(parent: Option<(u32, &Context)>, ctx: &Context) -> Self {
+        let base = parent.map(|(_, base)| base.clone()).unwrap_or_default();
+        DeltaNode {
+            parent: parent.map(|(index, _)| index),
+            deltas: diff_context(&base, ctx).expect("context can't be packed"),
+        }
+    }
+
+    fn get_analytic_size(&self) -> usize {
+        size_of::<DeltaNode>() + self.deltas.len() * size_of::<ContextDelta>()
+    }
+}
+
+// Handles are delta nodes against the parent's context, and the nodes of
+// every context used as a parent are kept so the handles can be decoded
+#[derive(Default)]
+pub struct ParentDeltaStorage {
+    // The parent of every context and the node of every stored parent, which
+    // every backend counts along with the nodes
+    parents: HashMap<Context, Context>,
+    index: HashMap<Context, u32>,
+    nodes: Vec<DeltaNode>,
+}
+
+impl ParentDeltaStorage {
+    // Store the nodes of ctx and every ancestor that isn't stored yet, top down
+    fn intern(&mut self, ctx: &Context) -> u32 {
+        let mut chain = vec![];
+        let mut current = Some(ctx.clone());
+        let mut parent = None;
+        while let Some(context) = current {
+            if let Some(&index) = self.index.get(&context) {
+                parent = Some((index, context));
+                break;
+            }
+            current = self.parents.get(&context).cloned();
+            chain.push(context);
+        }
+
+        for context in chain.into_iter().rev() {
+            let node = DeltaNode::new(parent.as_ref().map(|(index, base)| (*index, base)), &context);
+            let index = u32::try_from(self.nodes.len()).expect("too many parent contexts");
+            self.nodes.push(node);
+            self.index.insert(context.clone(), index);
+            parent = Some((index, context));
+        }
+        parent.unwrap().0
+    }
+
+    fn decode(&self, node: &DeltaNode) -> Context {
+        let mut chain = vec![node];
+        while let Some(parent) = chain.last().unwrap().parent {
+            chain.push(&self.nodes[parent as usize]);
+        }
+        chain
+            .iter()
+            .rev()
+            .fold(Context::default(), |ctx, node| apply_deltas(&ctx, &node.deltas))
+    }
+}
+
+impl ContextSize for ParentDeltaStorage {
+    type Context = Context;
+    type Pointer = DeltaNode;
+    type Storage = Vec<DeltaNode>;
+
+    fn get_storage(&self) -> Option<Vec<DeltaNode>> {
+        Some(self.nodes.clone())
+    }
+
+    fn get_storage_size(&self) -> usize {
+        self.parents.deep_size_of() + self.index.deep_size_of() + self.nodes.deep_size_of()
+    }
+
+    const NEEDS_TRAINING: bool = true;
+
+    fn train(&mut self, contexts: &[ContextWithCount]) {
+        self.parents = parent_contexts(contexts);
+    }
+
+    fn store_context(&mut self, context: Context) -> DeltaNode {
+        match self.parents.get(&context).cloned() {
+            Some(parent) => {
+                let index = self.intern(&parent);
+                DeltaNode::new(Some((index, &parent)), &context)
+            }
+            None => DeltaNode::new(None, &context),
+        }
+    }
+
+    fn load_context(&self, pointer: &DeltaNode) -> Context {
+        self.decode(pointer)
+    }
+
+    fn get_analytic_pointer_size(&self, pointer: &DeltaNode) -> usize {
+        pointer.get_analytic_size()
+    }
+
+    fn get_analytic_storage_size(&self) -> usize {
+        hash_table_size::<(Context, Context)>(self.parents.capacity())
+            + hash_table_size::<(Context, u32)>(self.index.capacity())
+            + self.nodes.capacity() * size_of::<DeltaNode>()
+            + self.nodes.iter().map(|node| node.deltas.len() * size_of::<ContextDelta>()).sum::<usize>()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::synthetic::{assert_roundtrip, Generator, GeneratorConfig};
+
+    #[test]
+    fn only_parents_get_nodes() {
+        let config = GeneratorConfig { seed: 10, contexts: 2000, successor: 0.6, ..Default::default() };
+        let records = Generator::new(config).dataset();
+        let mut storage = ParentDeltaStorage::default();
+        storage.train(&records);
+        let handles: Vec<DeltaNode> = records.iter().map(|record| storage.store_context(record.context.clone())).collect();
+
+        assert!(handles.iter().any(|handle| handle.parent.is_some()));
+        let parents: HashSet<&Context> = storage.parents.values().collect();
+        assert!(!storage.nodes.is_empty() && storage.nodes.len() <= parents.len());
+    }
+
+    #[test]
+    fn cycles_are_left_out() {
+        let ctx = |stack_size| Context { stack_size, sp_offset: stack_size as i16, ..Default::default() };
+        let record = |stack_size, block, parent| ContextWithCount {
+            context: ctx(stack_size),
+            count: 1,
+            block: Some(block),
+            parent: Some(parent),
+        };
+        // 1 -> 2 -> 3 -> 1, and 4 is its own parent
+        let records = [record(1, 1, 3), record(2, 2, 1), record(3, 3, 2), record(4, 4, 4)];
+
+        let parents = parent_contexts(&records);
+        assert_eq!(parents.len(), 2);
+        assert_eq!(parents[&ctx(1)], ctx(3));
+        assert_eq!(parents[&ctx(2)], ctx(1));
+        assert_roundtrip::<ParentDeltaStorage>(&records);
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+
+use crate::{bench::Timing, cli::Format, initial_context::ContextWithCount, measure::Measurement};
+
+// One strategy measured on one dataset
+#[derive(Clone, Debug, Serialize)]
+pub struct SizeResult {
+    pub dataset: String,
+    pub strategy: &'static str,
+    pub type_name: &'static str,
+    pub backend: &'static str,
+    pub total_bytes: usize,
+    pub pointer_bytes: usize,
+    pub storage_bytes: usize,
+    // Distinct contexts in the dataset
+    pub unique_contexts: usize,
+    // Contexts in the dataset counting every occurrence
+    pub total_contexts: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DatasetCounts {
+    pub unique_contexts: usize,
+    pub total_contexts: u64,
+}
+
+impl DatasetCounts {
+    pub fn new(contexts: &[ContextWithCount]) -> Self {
+        let unique: HashSet<_> = contexts.iter().map(|context| &context.context).collect();
+        DatasetCounts {
+            unique_contexts: unique.len(),
+            total_contexts: contexts.iter().map(|context| context.count).sum(),
+        }
+    }
+}
+
+impl SizeResult {
+    pub fn new(
+        dataset: &str,
+        strategy: &crate::Strategy,
+        backend: &'static str,
+        counts: DatasetCounts,
+        measurement: Measurement,
+    ) -> Self {
+        SizeResult {
+            dataset: dataset.to_string(),
+            strategy: strategy.name,
+            type_name: strategy.type_name,
+            backend,
+            total_bytes: measurement.bytes,
+            pointer_bytes: measurement.pointer_bytes,
+            storage_bytes: measurement.storage_bytes,
+            unique_contexts: counts.unique_contexts,
+            total_contexts: counts.total_contexts,
+        }
+    }
+}
+
+const CSV_HEADER: &str = "dataset,strategy,type_name,backend,total_bytes,pointer_bytes,storage_bytes,unique_contexts,total_contexts";
+
+// Quote a CSV field if it needs it
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+pub fn print_results(format: Format, results: &[SizeResult]) {
+    match format {
+        Format::Table => {
+            let multiple = results.iter().any(|result| result.dataset != results[0].dataset);
+            let mut dataset = None;
+            for result in results.iter() {
+                if multiple && dataset != Some(&result.dataset) {
+                    println!("{}", result.dataset);
+                    dataset = Some(&result.dataset);
+                }
+                println!("Total size {0: <80} {1: <10}", result.type_name, result.total_bytes);
+            }
+        }
+        Format::Tsv => {
+            for result in results.iter() {
+                println!("{}\t{}\t{}\t{}", result.dataset, result.strategy, result.type_name, result.total_bytes);
+            }
+        }
+        Format::Json => {
+            println!("{}", serde_json::to_string_pretty(results).unwrap());
+        }
+        Format::Csv => {
+            println!("{}", CSV_HEADER);
+            for result in results.iter() {
+                println!(
+                    "{},{},{},{},{},{},{},{},{}",
+                    csv_field(&result.dataset),
+                    csv_field(result.strategy),
+                    csv_field(result.type_name),
+                    result.backend,
+                    result.total_bytes,
+                    result.pointer_bytes,
+                    result.storage_bytes,
+                    result.unique_contexts,
+                    result.total_contexts,
+                );
+            }
+        }
+    }
+}
+
+
+// Timings of one strategy on one dataset
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchResult {
+    pub dataset: String,
+    pub strategy: &'static str,
+    pub type_name: &'static str,
+    // Contexts replayed per loop, counting every occurrence
+    pub operations: u64,
+    pub insert_ns: f64,
+    pub decode_ns: f64,
+    pub eq_ns: f64,
+    pub hash_ns: f64,
+}
+
+impl BenchResult {
+    pub fn new(dataset: &str, strategy: &crate::Strategy, timing: Timing) -> Self {
+        BenchResult {
+            dataset: dataset.to_string(),
+            strategy: strategy.name,
+            type_name: strategy.type_name,
+            operations: timing.operations,
+            insert_ns: timing.insert_ns,
+            decode_ns: timing.decode_ns,
+            eq_ns: timing.eq_ns,
+            hash_ns: timing.hash_ns,
+        }
+    }
+}
+
+const BENCH_CSV_HEADER: &str = "dataset,strategy,type_name,operations,insert_ns,decode_ns,eq_ns,hash_ns";
+
+pub fn print_bench_results(format: Format, results: &[BenchResult]) {
+    match format {
+        Format::Table => {
+            let mut dataset = None;
+            for result in results.iter() {
+                if dataset != Some(&result.dataset) {
+                    println!("{} ({} operations, ns/op)", result.dataset, result.operations);
+                    println!(
+                        "{0: <16} {1: >10} {2: >10} {3: >10} {4: >10}",
+                        "strategy", "insert", "decode", "eq", "hash"
+                    );
+                    dataset = Some(&result.dataset);
+                }
+                println!(
+                    "{0: <16} {1: >10.1} {2: >10.1} {3: >10.1} {4: >10.1}",
+                    result.strategy, result.insert_ns, result.decode_ns, result.eq_ns, result.hash_ns
+                );
+            }
+        }
+        Format::Tsv => {
+            for result in results.iter() {
+                println!(
+                    "{}\t{}\t{:.1}\t{:.1}\t{:.1}\t{:.1}",
+                    result.dataset, result.strategy, result.insert_ns, result.decode_ns, result.eq_ns, result.hash_ns
+                );
+            }
+        }
+        Format::Json => {
+            println!("{}", serde_json::to_string_pretty(results).unwrap());
+        }
+        Format::Csv => {
+            println!("{}", BENCH_CSV_HEADER);
+            for result in results.iter() {
+                println!(
+                    "{},{},{},{},{:.1},{:.1},{:.1},{:.1}",
+                    csv_field(&result.dataset),
+                    csv_field(result.strategy),
+                    csv_field(result.type_name),
+                    result.operations,
+                    result.insert_ns,
+                    result.decode_ns,
+                    result.eq_ns,
+                    result.hash_ns,
+                );
+            }
+        }
+    }
+}
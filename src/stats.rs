@@ -0,0 +1,400 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+
+use crate::{
+    cli::Format,
+    initial_context::{Context, ContextWithCount, TempMapping, Type, TypeDiff, MAX_LOCAL_TYPES, MAX_TEMP_TYPES},
+    packed_context::{diff_context, pack_context},
+    parent_delta::parent_contexts,
+    report::{csv_field, DatasetCounts},
+};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Bucket {
+    pub label: String,
+    // Records in the dataset that fall in the bucket
+    pub contexts: u64,
+    // The same weighted by how often each context is used
+    pub weighted: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Histogram {
+    pub name: String,
+    pub buckets: Vec<Bucket>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DatasetStats {
+    pub dataset: String,
+    pub unique_contexts: usize,
+    pub total_contexts: u64,
+    // Total contexts per unique one
+    pub dedup_ratio: f64,
+    // Unique contexts that a block compiled for another one would accept, by
+    // Context::diff, and how often they're used
+    pub subsumed_contexts: usize,
+    pub subsumed_weighted: u64,
+    // The subsumed contexts that a more general one turns into by upgrading
+    // its types alone, as type guards would, with the same temp mappings
+    pub upgradable_contexts: usize,
+    pub histograms: Vec<Histogram>,
+}
+
+// Builds a histogram keyed by something that sorts into a sensible order
+struct Counter<K> {
+    name: String,
+    counts: BTreeMap<K, (String, u64, u64)>,
+}
+
+impl<K: Ord> Counter<K> {
+    fn new(name: &str) -> Self {
+        Counter { name: name.to_string(), counts: BTreeMap::new() }
+    }
+
+    fn add(&mut self, key: K, label: impl FnOnce() -> String, count: u64) {
+        let entry = self.counts.entry(key).or_insert_with(|| (label(), 0, 0));
+        entry.1 += 1;
+        entry.2 += count;
+    }
+
+    fn finish(self) -> Histogram {
+        Histogram {
+            name: self.name,
+            buckets: self
+                .counts
+                .into_values()
+                .map(|(label, contexts, weighted)| Bucket { label, contexts, weighted })
+                .collect(),
+        }
+    }
+}
+
+// Exact values up to 15, then power of two ranges so deep stacks don't get a
+// row each
+fn magnitude_bucket(value: i64) -> (i64, String) {
+    let magnitude = value.unsigned_abs();
+    if magnitude < 16 {
+        return (value, value.to_string());
+    }
+    let low = 1u64 << (63 - magnitude.leading_zeros());
+    let high = low * 2 - 1;
+    if value < 0 {
+        (-(low as i64), format!("-{}..-{}", high, low))
+    } else {
+        (low as i64, format!("{}..{}", low, high))
+    }
+}
+
+// Which kind of value a type is, 0 for Unknown. Type::diff only lets a known
+// type flow into one of the same kind.
+fn type_family(ty: Type) -> u64 {
+    if ty.is_imm() {
+        1
+    } else if ty.is_heap() {
+        2
+    } else {
+        0
+    }
+}
+
+const _: () = assert!(2 * (1 + MAX_LOCAL_TYPES) <= 64);
+
+// The family of self and of every local, two bits each
+fn family_key(ctx: &Context) -> u64 {
+    std::iter::once(ctx.self_type)
+        .chain(ctx.local_types.iter().copied())
+        .enumerate()
+        .fold(0, |key, (i, ty)| key | type_family(ty) << (2 * i))
+}
+
+// Whether a context with the src key can be compatible with one with the dst
+// key: every slot whose family dst knows has the same family in src
+fn families_compatible(src: u64, dst: u64) -> bool {
+    let known = (dst | dst >> 1) & 0x5555_5555_5555_5555;
+    src & (known * 3) == dst
+}
+
+// Unique contexts with the same family key, and their counts
+type FamilyBucket<'a> = HashMap<&'a Context, u64>;
+
+// Whether the types of general can be upgraded into exactly ctx
+fn upgrades_to(general: &Context, ctx: &Context) -> bool {
+    let mut upgraded = general.clone();
+    let types = std::iter::once((&mut upgraded.self_type, ctx.self_type))
+        .chain(upgraded.local_types.iter_mut().zip(ctx.local_types.iter().copied()))
+        .chain(upgraded.temp_types.iter_mut().zip(ctx.temp_types.iter().copied()));
+    for (ty, src) in types {
+        if src.diff(*ty) == TypeDiff::Incompatible {
+            return false;
+        }
+        ty.upgrade(src);
+    }
+    upgraded == *ctx
+}
+
+// Unique contexts strictly subsumed by another context in the dataset, their
+// total count, and how many of them upgrades_to reaches. Context::diff only matches contexts with the same stack
+// and no chain depth, and within those only contexts whose types are of the
+// same families where the other one knows them, so only those are compared.
+fn subsumed_contexts(contexts: &[ContextWithCount]) -> (usize, u64, usize) {
+    let mut groups: HashMap<(u16, i16), HashMap<u64, FamilyBucket>> = HashMap::new();
+    for context in contexts.iter().filter(|context| context.context.chain_depth == 0) {
+        let ctx = &context.context;
+        let group = groups.entry((ctx.stack_size, ctx.sp_offset)).or_default();
+        *group.entry(family_key(ctx)).or_default().entry(ctx).or_insert(0) += context.count;
+    }
+
+    let (mut unique, mut weighted, mut upgradable) = (0, 0, 0);
+    for group in groups.values() {
+        for (&key, bucket) in group.iter() {
+            let candidates: Vec<&Context> = group
+                .iter()
+                .filter(|(&other_key, _)| families_compatible(key, other_key))
+                .flat_map(|(_, other_bucket)| other_bucket.keys().copied())
+                .collect();
+            for (ctx, &count) in bucket.iter() {
+                let mut general = candidates
+                    .iter()
+                    .filter(|other| matches!(ctx.diff(other), TypeDiff::Compatible(diff) if diff > 0));
+                if let Some(first) = general.next() {
+                    unique += 1;
+                    weighted += count;
+                    if upgrades_to(first, ctx) || general.any(|other| upgrades_to(other, ctx)) {
+                        upgradable += 1;
+                    }
+                }
+            }
+        }
+    }
+    (unique, weighted, upgradable)
+}
+
+pub fn dataset_stats(dataset: &str, contexts: &[ContextWithCount]) -> DatasetStats {
+    let counts = DatasetCounts::new(contexts);
+
+    let mut stack_size = Counter::new("stack_size");
+    let mut sp_offset = Counter::new("sp_offset");
+    let mut chain_depth = Counter::new("chain_depth");
+    let mut self_type = Counter::new("self_type");
+    let mut local_types: Vec<Counter<Type>> =
+        (0..MAX_LOCAL_TYPES).map(|i| Counter::new(&format!("local_types[{}]", i))).collect();
+    let mut temp_types: Vec<Counter<Type>> =
+        (0..MAX_TEMP_TYPES).map(|i| Counter::new(&format!("temp_types[{}]", i))).collect();
+    let mut temp_mapping = Counter::new("temp_mapping");
+    let mut deltas = Counter::new("pack_context deltas");
+    let parents = parent_contexts(contexts);
+    let mut parent_deltas = Counter::new("deltas from parent");
+
+    for context in contexts.iter() {
+        let ctx = &context.context;
+        let count = context.count;
+
+        let (key, label) = magnitude_bucket(ctx.stack_size as i64);
+        stack_size.add(key, || label, count);
+        let (key, label) = magnitude_bucket(ctx.sp_offset as i64);
+        sp_offset.add(key, || label, count);
+        chain_depth.add(ctx.chain_depth, || ctx.chain_depth.to_string(), count);
+        self_type.add(ctx.self_type, || format!("{:?}", ctx.self_type), count);
+
+        for (counter, &ty) in local_types.iter_mut().zip(ctx.local_types.iter()) {
+            counter.add(ty, || format!("{:?}", ty), count);
+        }
+        for (counter, &ty) in temp_types.iter_mut().zip(ctx.temp_types.iter()) {
+            counter.add(ty, || format!("{:?}", ty), count);
+        }
+        // One entry per temp slot, MapToLocal split by the local it maps to
+        for &mapping in ctx.temp_mapping.iter() {
+            let key = match mapping {
+                TempMapping::MapToStack => 0,
+                TempMapping::MapToSelf => 1,
+                TempMapping::MapToLocal(i) => 2 + i as u16,
+            };
+            temp_mapping.add(key, || format!("{:?}", mapping), count);
+        }
+
+        let len = pack_context(ctx).map(|packed| packed.len()).unwrap_or(0);
+        deltas.add(len, || len.to_string(), count);
+
+        if let Some(parent) = parents.get(ctx) {
+            let len = diff_context(parent, ctx).map(|packed| packed.len()).unwrap_or(0);
+            parent_deltas.add(len, || len.to_string(), count);
+        }
+    }
+
+    let mut histograms = vec![
+        stack_size.finish(),
+        sp_offset.finish(),
+        chain_depth.finish(),
+        self_type.finish(),
+    ];
+    histograms.extend(local_types.into_iter().map(Counter::finish));
+    histograms.extend(temp_types.into_iter().map(Counter::finish));
+    histograms.push(temp_mapping.finish());
+    histograms.push(deltas.finish());
+    if !parents.is_empty() {
+        histograms.push(parent_deltas.finish());
+    }
+
+    let (subsumed_contexts, subsumed_weighted, upgradable_contexts) = subsumed_contexts(contexts);
+    DatasetStats {
+        dataset: dataset.to_string(),
+        unique_contexts: counts.unique_contexts,
+        total_contexts: counts.total_contexts,
+        dedup_ratio: counts.total_contexts as f64 / counts.unique_contexts.max(1) as f64,
+        subsumed_contexts,
+        subsumed_weighted,
+        upgradable_contexts,
+        histograms,
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    part as f64 * 100.0 / whole.max(1) as f64
+}
+
+pub fn print_stats(format: Format, stats: &[DatasetStats]) {
+    match format {
+        Format::Table => {
+            for stats in stats.iter() {
+                println!("{}", stats.dataset);
+                println!("  unique contexts  {}", stats.unique_contexts);
+                println!("  total contexts   {}", stats.total_contexts);
+                println!("  dedup ratio      {:.2}", stats.dedup_ratio);
+                println!(
+                    "  subsumed         {} ({:.2}%), {} weighted ({:.2}%)",
+                    stats.subsumed_contexts,
+                    percent(stats.subsumed_contexts as u64, stats.unique_contexts as u64),
+                    stats.subsumed_weighted,
+                    percent(stats.subsumed_weighted, stats.total_contexts),
+                );
+                println!(
+                    "  by upgrading     {} ({:.2}%)",
+                    stats.upgradable_contexts,
+                    percent(stats.upgradable_contexts as u64, stats.unique_contexts as u64),
+                );
+                let records: u64 = stats.histograms[0].buckets.iter().map(|bucket| bucket.contexts).sum();
+                for histogram in stats.histograms.iter() {
+                    println!();
+                    println!(
+                        "  {0: <24} {1: >10} {2: >7} {3: >12} {4: >7}",
+                        histogram.name, "contexts", "%", "weighted", "%"
+                    );
+                    for bucket in histogram.buckets.iter() {
+                        println!(
+                            "  {0: <24} {1: >10} {2: >7.2} {3: >12} {4: >7.2}",
+                            bucket.label,
+                            bucket.contexts,
+                            percent(bucket.contexts, records),
+                            bucket.weighted,
+                            percent(bucket.weighted, stats.total_contexts),
+                        );
+                    }
+                }
+            }
+        }
+        Format::Tsv => {
+            for stats in stats.iter() {
+                for histogram in stats.histograms.iter() {
+                    for bucket in histogram.buckets.iter() {
+                        println!(
+                            "{}\t{}\t{}\t{}\t{}",
+                            stats.dataset, histogram.name, bucket.label, bucket.contexts, bucket.weighted
+                        );
+                    }
+                }
+            }
+        }
+        Format::Json => {
+            println!("{}", serde_json::to_string_pretty(stats).unwrap());
+        }
+        Format::Csv => {
+            println!("dataset,histogram,bucket,contexts,weighted");
+            for stats in stats.iter() {
+                for histogram in stats.histograms.iter() {
+                    for bucket in histogram.buckets.iter() {
+                        println!(
+                            "{},{},{},{},{}",
+                            csv_field(&stats.dataset),
+                            csv_field(&histogram.name),
+                            csv_field(&bucket.label),
+                            bucket.contexts,
+                            bucket.weighted
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::{test_contexts, test_records};
+
+    #[test]
+    fn family_keys_keep_every_compatible_pair() {
+        let contexts: Vec<Context> = test_contexts(13, 600)
+            .into_iter()
+            .map(|mut ctx| {
+                // Few enough stacks that most pairs are actually compared
+                (ctx.stack_size, ctx.sp_offset, ctx.chain_depth) = (ctx.stack_size % 3, ctx.stack_size as i16 % 3, 0);
+                ctx
+            })
+            .collect();
+
+        let mut subsumed = 0;
+        for ctx in contexts.iter() {
+            for other in contexts.iter() {
+                if let TypeDiff::Compatible(_) = ctx.diff(other) {
+                    assert!(families_compatible(family_key(ctx), family_key(other)), "{:?} into {:?}", ctx, other);
+                }
+            }
+            if contexts.iter().any(|other| matches!(ctx.diff(other), TypeDiff::Compatible(diff) if diff > 0)) {
+                subsumed += 1;
+            }
+        }
+        assert!(subsumed > 0);
+
+        let unique: std::collections::HashSet<&Context> = contexts.iter().collect();
+        let general = |ctx: &Context| -> Vec<&Context> {
+            unique
+                .iter()
+                .copied()
+                .filter(|other| matches!(ctx.diff(other), TypeDiff::Compatible(diff) if diff > 0))
+                .collect()
+        };
+        let subsumed_unique = unique.iter().filter(|ctx| !general(ctx).is_empty()).count();
+        let upgradable = unique.iter().filter(|ctx| general(ctx).iter().any(|other| upgrades_to(other, ctx))).count();
+        assert_eq!(
+            subsumed_contexts(&test_records(&contexts)),
+            (subsumed_unique, subsumed as u64, upgradable)
+        );
+    }
+
+    #[test]
+    fn only_type_upgrades_are_upgradable() {
+        let mut general = Context::default();
+        general.local_types[0] = Type::UnknownImm;
+
+        let mut guarded = general.clone();
+        guarded.self_type = Type::TString;
+        guarded.local_types[0] = Type::Fixnum;
+        assert!(upgrades_to(&general, &guarded));
+        assert!(!upgrades_to(&guarded, &general));
+
+        // Dropping a temp mapping is compatible but isn't an upgrade
+        let mut mapped = general.clone();
+        mapped.stack_size = 1;
+        mapped.temp_mapping[0] = TempMapping::MapToSelf;
+        let unmapped = Context { stack_size: 1, ..general.clone() };
+        assert!(matches!(mapped.diff(&unmapped), TypeDiff::Compatible(diff) if diff > 0));
+        assert!(!upgrades_to(&unmapped, &mapped));
+
+        let records = test_records(&[general, guarded, mapped, unmapped]);
+        assert_eq!(subsumed_contexts(&records), (2, 2, 1));
+    }
+}
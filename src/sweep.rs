@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+
+use deepsize::DeepSizeOf;
+use serde::Serialize;
+
+use crate::{
+    cli::Format,
+    compact_temp_mapping,
+    initial_context::{Context, ContextWithCount},
+    packed_context::pack_context,
+    report::csv_field,
+};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SweepResult {
+    pub dataset: String,
+    pub locals: usize,
+    pub temps: usize,
+    // Distinct contexts left once slots past the limits are dropped
+    pub unique_contexts: usize,
+    pub initial_bytes: usize,
+    pub compact_bytes: usize,
+    pub packed_bytes: usize,
+}
+
+// Each of the three stores every context on its own, so their sizes are what
+// the deepsize backend would measure
+fn sweep_limits<const LOCALS: usize, const TEMPS: usize>(dataset: &str, contexts: &[ContextWithCount]) -> SweepResult {
+    let mut unique = HashSet::new();
+    let mut result = SweepResult {
+        dataset: dataset.to_string(),
+        locals: LOCALS,
+        temps: TEMPS,
+        unique_contexts: 0,
+        initial_bytes: 0,
+        compact_bytes: 0,
+        packed_bytes: 0,
+    };
+    for context in contexts.iter() {
+        let ctx: Context<LOCALS, TEMPS> = context.context.resize();
+        let count = context.count as usize;
+        result.initial_bytes += ctx.deep_size_of() * count;
+        result.compact_bytes += compact_temp_mapping::Context::from(ctx.clone()).deep_size_of() * count;
+        result.packed_bytes += pack_context(&ctx).expect("context can't be packed").deep_size_of() * count;
+        unique.insert(ctx);
+    }
+    result.unique_contexts = unique.len();
+    result
+}
+
+// Datasets only record the slots YJIT tracks, so the wider limits show what
+// the bigger struct costs rather than what the extra slots would hold
+pub fn sweep(dataset: &str, contexts: &[ContextWithCount]) -> Vec<SweepResult> {
+    vec![
+        sweep_limits::<4, 4>(dataset, contexts),
+        sweep_limits::<4, 8>(dataset, contexts),
+        sweep_limits::<4, 16>(dataset, contexts),
+        sweep_limits::<8, 4>(dataset, contexts),
+        sweep_limits::<8, 8>(dataset, contexts),
+        sweep_limits::<8, 16>(dataset, contexts),
+        sweep_limits::<16, 4>(dataset, contexts),
+        sweep_limits::<16, 8>(dataset, contexts),
+        sweep_limits::<16, 16>(dataset, contexts),
+    ]
+}
+
+pub fn print_sweep(format: Format, results: &[SweepResult]) {
+    match format {
+        Format::Table => {
+            let mut dataset = None;
+            for result in results.iter() {
+                if dataset != Some(&result.dataset) {
+                    dataset = Some(&result.dataset);
+                    println!("{}", result.dataset);
+                    println!(
+                        "{0: >6} {1: >6} {2: >10} {3: >12} {4: >12} {5: >12}",
+                        "locals", "temps", "unique", "initial", "compact", "packed"
+                    );
+                }
+                println!(
+                    "{0: >6} {1: >6} {2: >10} {3: >12} {4: >12} {5: >12}",
+                    result.locals,
+                    result.temps,
+                    result.unique_contexts,
+                    result.initial_bytes,
+                    result.compact_bytes,
+                    result.packed_bytes
+                );
+            }
+        }
+        Format::Tsv => {
+            for result in results.iter() {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    result.dataset,
+                    result.locals,
+                    result.temps,
+                    result.unique_contexts,
+                    result.initial_bytes,
+                    result.compact_bytes,
+                    result.packed_bytes
+                );
+            }
+        }
+        Format::Json => {
+            println!("{}", serde_json::to_string_pretty(results).unwrap());
+        }
+        Format::Csv => {
+            println!("dataset,locals,temps,unique_contexts,initial_bytes,compact_bytes,packed_bytes");
+            for result in results.iter() {
+                println!(
+                    "{},{},{},{},{},{},{}",
+                    csv_field(&result.dataset),
+                    result.locals,
+                    result.temps,
+                    result.unique_contexts,
+                    result.initial_bytes,
+                    result.compact_bytes,
+                    result.packed_bytes
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::HashSet;
+
+use crate::initial_context::{Context, ContextWithCount, TempMapping, Type, MAX_LOCAL_TYPES, MAX_TEMP_TYPES};
+
+// Small seeded PRNG (SplitMix64) so generated datasets are the same on every
+// machine without pulling in a dependency
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Uniform in [0, n)
+    pub fn below(&mut self, n: u64) -> u64 {
+        assert!(n > 0);
+        self.next_u64() % n
+    }
+
+    pub fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+
+    // Number of failures before the first success for a geometric
+    // distribution with the given mean
+    pub fn geometric(&mut self, mean: f64) -> u64 {
+        if mean <= 0.0 {
+            return 0;
+        }
+        let p = 1.0 / (mean + 1.0);
+        let u = 1.0 - self.next_f64();
+        (u.ln() / (1.0 - p).ln()).floor() as u64
+    }
+
+    pub fn weighted<'a, T>(&mut self, weights: &'a [(T, u32)]) -> &'a T {
+        let total: u64 = weights.iter().map(|(_, weight)| *weight as u64).sum();
+        let mut pick = self.below(total);
+        for (value, weight) in weights.iter() {
+            if pick < *weight as u64 {
+                return value;
+            }
+            pick -= *weight as u64;
+        }
+        unreachable!()
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeneratorConfig {
+    pub seed: u64,
+    // Number of distinct contexts to generate
+    pub contexts: usize,
+    // Zipf exponent for how often each context is used, 0 means no duplication skew
+    pub skew: f64,
+    // Count of the most used context
+    pub max_count: u64,
+    // Mean of the geometric distribution of stack_size
+    pub stack_mean: f64,
+    // Mean of the geometric distribution of chain_depth
+    pub chain_depth_mean: f64,
+    // Chance the first local/temp slot has a known type
+    pub local_known: f64,
+    pub temp_known: f64,
+    // Each following slot is this much less likely to have a known type
+    pub slot_decay: f64,
+    // Relative frequency of each known type
+    pub types: Vec<(Type, u32)>,
+    // Chance a temp on the stack maps to self or a local
+    pub temp_mapping: f64,
+    // Chance a context is a successor of an earlier one, differing from it in
+    // a field or two. Contexts get block ids when this isn't 0.
+    pub successor: f64,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        GeneratorConfig {
+            seed: 0,
+            contexts: 10_000,
+            skew: 1.0,
+            max_count: 1_000,
+            stack_mean: 2.0,
+            chain_depth_mean: 0.3,
+            local_known: 0.5,
+            temp_known: 0.7,
+            slot_decay: 0.7,
+            types: vec![
+                (Type::Fixnum, 8),
+                (Type::UnknownHeap, 4),
+                (Type::CString, 4),
+                (Type::UnknownImm, 2),
+                (Type::CArray, 2),
+                (Type::Nil, 2),
+                (Type::TString, 1),
+                (Type::TArray, 1),
+                (Type::Hash, 1),
+                (Type::True, 1),
+                (Type::False, 1),
+                (Type::Flonum, 1),
+                (Type::ImmSymbol, 1),
+                (Type::BlockParamProxy, 1),
+            ],
+            temp_mapping: 0.3,
+            successor: 0.0,
+        }
+    }
+}
+
+// Parse type weights written as `Fixnum=4,CString=2`
+pub fn parse_type_weights(text: &str) -> Result<Vec<(Type, u32)>, String> {
+    let weights: Vec<(Type, u32)> = text
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, weight) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected TYPE=WEIGHT, got `{}`", entry))?;
+            let ty: Type = serde_json::from_value(serde_json::Value::String(name.to_string()))
+                .map_err(|_| format!("unknown type `{}`", name))?;
+            let weight = weight.parse().map_err(|_| format!("invalid weight `{}`", weight))?;
+            Ok((ty, weight))
+        })
+        .collect::<Result<_, String>>()?;
+    if weights.iter().all(|(_, weight)| *weight == 0) {
+        return Err("type weights must not all be zero".to_string());
+    }
+    Ok(weights)
+}
+
+pub struct Generator {
+    config: GeneratorConfig,
+    rng: Rng,
+}
+
+impl Generator {
+    pub fn new(config: GeneratorConfig) -> Self {
+        let rng = Rng::new(config.seed);
+        Generator { config, rng }
+    }
+
+    fn known_type(&mut self, known: f64) -> Type {
+        if self.rng.chance(known) {
+            *self.rng.weighted(&self.config.types)
+        } else {
+            Type::Unknown
+        }
+    }
+
+    // A context that YJIT could produce: temps above the stack are untouched
+    // and mapped temps take their type from what they map to
+    pub fn context(&mut self) -> Context {
+        let mut ctx = Context::default();
+
+        ctx.stack_size = self.rng.geometric(self.config.stack_mean).min(u16::MAX as u64) as u16;
+        ctx.sp_offset = if self.rng.chance(0.9) {
+            ctx.stack_size.min(i16::MAX as u16) as i16
+        } else {
+            ctx.stack_size.min(i16::MAX as u16) as i16 - self.rng.below(3) as i16
+        };
+        ctx.chain_depth = self.rng.geometric(self.config.chain_depth_mean).min(u8::MAX as u64) as u8;
+
+        if self.rng.chance(0.5) {
+            ctx.self_type = Type::UnknownHeap;
+        }
+
+        let mut known = self.config.local_known;
+        for i in 0..MAX_LOCAL_TYPES {
+            ctx.local_types[i] = self.known_type(known);
+            known *= self.config.slot_decay;
+        }
+
+        let mut known = self.config.temp_known;
+        for i in 0..MAX_TEMP_TYPES.min(ctx.stack_size as usize) {
+            if self.rng.chance(self.config.temp_mapping) {
+                ctx.temp_mapping[i] = if self.rng.chance(0.2) {
+                    TempMapping::MapToSelf
+                } else {
+                    TempMapping::MapToLocal(self.rng.below(MAX_LOCAL_TYPES as u64) as u8)
+                };
+            } else {
+                ctx.temp_types[i] = self.known_type(known);
+            }
+            known *= self.config.slot_decay;
+        }
+
+        ctx
+    }
+
+    // What a block after one with the given context could have: a push, a
+    // pop, or a local with a newly learned type
+    pub fn successor(&mut self, parent: &Context) -> Context {
+        let mut ctx = parent.clone();
+        match self.rng.below(3) {
+            0 if ctx.stack_size < u16::MAX && ctx.sp_offset < i16::MAX => {
+                let top = ctx.stack_size as usize;
+                ctx.stack_size += 1;
+                ctx.sp_offset += 1;
+                if top < MAX_TEMP_TYPES {
+                    ctx.temp_types[top] = self.known_type(self.config.temp_known);
+                }
+            }
+            1 if ctx.stack_size > 0 && ctx.sp_offset > i16::MIN => {
+                ctx.stack_size -= 1;
+                ctx.sp_offset -= 1;
+                let top = ctx.stack_size as usize;
+                if top < MAX_TEMP_TYPES {
+                    ctx.temp_types[top] = Type::Unknown;
+                    ctx.temp_mapping[top] = TempMapping::MapToStack;
+                }
+            }
+            _ => {
+                let local = self.rng.below(MAX_LOCAL_TYPES as u64) as usize;
+                ctx.local_types[local] = self.known_type(1.0);
+            }
+        }
+        ctx
+    }
+
+    // Generate up to config.contexts distinct contexts, stopping early when the
+    // distributions don't have that many to give
+    pub fn dataset(&mut self) -> Vec<ContextWithCount> {
+        let mut seen = HashSet::new();
+        let mut contexts: Vec<(Context, Option<usize>)> = vec![];
+        let mut attempts = 0;
+        while contexts.len() < self.config.contexts && attempts < self.config.contexts * 100 {
+            attempts += 1;
+            // Only draw for successors when they're enabled, so datasets
+            // without them stay the same for a seed
+            let (context, parent) = if self.config.successor > 0.0 && !contexts.is_empty() && self.rng.chance(self.config.successor) {
+                let parent = self.rng.below(contexts.len() as u64) as usize;
+                (self.successor(&contexts[parent].0), Some(parent))
+            } else {
+                (self.context(), None)
+            };
+            if seen.insert(context.clone()) {
+                contexts.push((context, parent));
+            }
+        }
+
+        let blocks = self.config.successor > 0.0;
+        contexts
+            .into_iter()
+            .enumerate()
+            .map(|(rank, (context, parent))| {
+                let count = self.config.max_count as f64 / ((rank + 1) as f64).powf(self.config.skew);
+                ContextWithCount {
+                    context,
+                    count: (count.round() as u64).max(1),
+                    block: if blocks { Some(rank as u64) } else { None },
+                    parent: parent.map(|parent| parent as u64),
+                }
+            })
+            .collect()
+    }
+}
+
+
+// Any context pack_context accepts rather than one YJIT would make, with the
+// numeric fields often at the edges of their encodings
+#[cfg(test)]
+pub fn arbitrary_context(rng: &mut Rng) -> Context {
+    let mut ctx = Context::default();
+    ctx.stack_size = match rng.below(4) {
+        0 => *rng.weighted(&[(0, 1), (1, 1), (255, 1), (256, 1), (u16::MAX, 1)]),
+        1 => rng.below(1 << 16) as u16,
+        _ => rng.below(12) as u16,
+    };
+    ctx.sp_offset = match rng.below(4) {
+        0 => *rng.weighted(&[(0, 1), (-1, 1), (127, 1), (-128, 1), (128, 1), (i16::MIN, 1), (i16::MAX, 1)]),
+        1 => rng.below(1 << 16) as u16 as i16,
+        _ => ctx.stack_size.min(i16::MAX as u16) as i16,
+    };
+    ctx.chain_depth = if rng.chance(0.5) { 0 } else { rng.below(256) as u8 };
+
+    let any_type = |rng: &mut Rng| {
+        if rng.chance(0.3) {
+            Type::Unknown
+        } else {
+            Type::ALL[rng.below(Type::ALL.len() as u64) as usize]
+        }
+    };
+    ctx.self_type = any_type(rng);
+    for ty in ctx.local_types.iter_mut().chain(ctx.temp_types.iter_mut()) {
+        *ty = any_type(rng);
+    }
+    for mapping in ctx.temp_mapping.iter_mut() {
+        *mapping = match rng.below(4) {
+            0 => TempMapping::MapToSelf,
+            1 => TempMapping::MapToLocal(rng.below(MAX_LOCAL_TYPES as u64) as u8),
+            _ => TempMapping::MapToStack,
+        };
+    }
+    ctx
+}
+
+// Arbitrary contexts mixed with ones from the generator, some of them drawn
+// again later so equal contexts come up as separate values
+#[cfg(test)]
+pub fn test_contexts(seed: u64, len: usize) -> Vec<Context> {
+    let mut rng = Rng::new(seed);
+    let config = GeneratorConfig { seed, contexts: len, ..Default::default() };
+    let mut generated = Generator::new(config).dataset().into_iter().map(|context| context.context);
+
+    let mut contexts: Vec<Context> = vec![Context::default()];
+    while contexts.len() < len {
+        let ctx = match rng.below(4) {
+            0 => contexts[rng.below(contexts.len() as u64) as usize].clone(),
+            1 => generated.next().unwrap_or_default(),
+            _ => arbitrary_context(&mut rng),
+        };
+        contexts.push(ctx);
+    }
+    contexts
+}
+
+// Handles must be equal exactly when the contexts they were made from are
+#[cfg(test)]
+pub fn assert_equal_handles<H: PartialEq + std::fmt::Debug>(contexts: &[Context], handles: &[H]) {
+    assert_eq!(contexts.len(), handles.len());
+    for i in 0..contexts.len() {
+        for j in 0..i {
+            assert_eq!(
+                contexts[i] == contexts[j],
+                handles[i] == handles[j],
+                "{:?} and {:?} gave {:?} and {:?}",
+                contexts[i], contexts[j], handles[i], handles[j],
+            );
+        }
+    }
+}
+
+// Records of the contexts, each seen once and without block ids
+#[cfg(test)]
+pub fn test_records(contexts: &[Context]) -> Vec<ContextWithCount> {
+    contexts
+        .iter()
+        .map(|context| ContextWithCount { context: context.clone(), count: 1, block: None, parent: None })
+        .collect()
+}
+
+// Store every record in a strategy trained on them, then check each handle
+// loads back to its context and that the handles are equal exactly when the
+// contexts are. Returns the strategy and handles for further checks.
+#[cfg(test)]
+pub fn assert_roundtrip<T>(records: &[ContextWithCount]) -> (T, Vec<T::Pointer>)
+where
+    T: crate::ContextSize + Default,
+    T::Context: From<Context>,
+    T::Pointer: std::fmt::Debug,
+{
+    let mut storage = T::default();
+    storage.train(records);
+    let handles: Vec<T::Pointer> = records.iter().map(|record| storage.store_context(record.context.clone().into())).collect();
+
+    let contexts: Vec<Context> = records.iter().map(|record| record.context.clone()).collect();
+    for (context, handle) in contexts.iter().zip(handles.iter()) {
+        assert_eq!(storage.load_context(handle), *context);
+    }
+    assert_equal_handles(&contexts, &handles);
+    (storage, handles)
+}
+
+// assert_roundtrip for the strategy list, which only needs the checks
+#[cfg(test)]
+pub fn check_round_trip<T>(records: &[ContextWithCount])
+where
+    T: crate::ContextSize + Default,
+    T::Context: From<Context>,
+    T::Pointer: std::fmt::Debug,
+{
+    assert_roundtrip::<T>(records);
+}
@@ -0,0 +1,199 @@
+use std::mem::size_of;
+
+use deepsize::DeepSizeOf;
+
+use crate::{
+    initial_context::{self, TempMapping, Type, MAX_LOCAL_TYPES, MAX_TEMP_TYPES},
+    ContextSize,
+};
+
+// chain_depth_and_flags: the low 6 bits are the chain depth, the top two are
+// whether the block is deferred and whether it's a return landing
+const CHAIN_DEPTH_MAX: u8 = 0x3f;
+
+// Bits per slot in local_types, temp_mapping_kind and temp_payload
+const TYPE_BITS: usize = 4;
+const KIND_BITS: usize = 2;
+
+// temp_mapping_kind values
+const MAP_TO_STACK: u16 = 0;
+const MAP_TO_SELF: u16 = 1;
+const MAP_TO_LOCAL: u16 = 2;
+
+/// Code generation context with the layout upstream YJIT uses now: the stack
+/// fields shrank to a byte, chain_depth shares its byte with flags, types and
+/// temp mappings are bit packed, and there's a bitmask of the stack temps held
+/// in registers plus the block iseq the caller inlined.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug, DeepSizeOf)]
+pub struct Context {
+    // Number of values currently on the temporary stack
+    stack_size: u8,
+
+    // Offset of the JIT SP relative to the interpreter SP
+    sp_offset: i8,
+
+    // Bit i is set when stack temp i is held in a register
+    reg_temps: u8,
+
+    // Depth in the sidechain plus the deferred and return landing flags
+    chain_depth_and_flags: u8,
+
+    // Type we track for self
+    self_type: Type,
+
+    // 4 bits of Type for each local
+    local_types: u32,
+
+    // 2 bits for each temp: on the stack, self or a local
+    temp_mapping_kind: u16,
+
+    // 4 bits for each temp: its Type on the stack, or the local it maps to
+    temp_payload: u32,
+
+    // Pointer to the block iseq supplied by the caller, 0 when not inlined
+    inline_block: u64,
+}
+
+const _: () = assert!(size_of::<Context>() == 24);
+const _: () = assert!(MAX_LOCAL_TYPES * TYPE_BITS <= 32 && MAX_TEMP_TYPES * TYPE_BITS <= 32);
+const _: () = assert!(MAX_TEMP_TYPES * KIND_BITS <= 16 && MAX_LOCAL_TYPES <= 1 << TYPE_BITS);
+
+fn decode_type(bits: u32) -> Type {
+    Type::ALL[(bits & 0xf) as usize]
+}
+
+impl Context {
+    fn local_type(&self, i: usize) -> Type {
+        decode_type(self.local_types >> (i * TYPE_BITS))
+    }
+
+    fn temp_mapping(&self, i: usize) -> (TempMapping, Type) {
+        let payload = (self.temp_payload >> (i * TYPE_BITS)) & 0xf;
+        match (self.temp_mapping_kind >> (i * KIND_BITS)) & 0x3 {
+            MAP_TO_SELF => (TempMapping::MapToSelf, Type::Unknown),
+            MAP_TO_LOCAL => (TempMapping::MapToLocal(payload as u8), Type::Unknown),
+            _ => (TempMapping::MapToStack, decode_type(payload)),
+        }
+    }
+}
+
+// Fails for the contexts upstream can't represent rather than clamping them
+impl TryFrom<&initial_context::Context> for Context {
+    type Error = String;
+
+    fn try_from(ctx: &initial_context::Context) -> Result<Self, String> {
+        let stack_size = u8::try_from(ctx.stack_size)
+            .map_err(|_| format!("stack_size {} doesn't fit in a byte", ctx.stack_size))?;
+        let sp_offset = i8::try_from(ctx.sp_offset)
+            .map_err(|_| format!("sp_offset {} doesn't fit in a byte", ctx.sp_offset))?;
+        if ctx.chain_depth > CHAIN_DEPTH_MAX {
+            return Err(format!("chain_depth {} is over {}", ctx.chain_depth, CHAIN_DEPTH_MAX));
+        }
+        // A mapped temp takes its type from what it maps to
+        if let Some(i) = (0..MAX_TEMP_TYPES)
+            .find(|&i| ctx.temp_mapping[i] != TempMapping::MapToStack && ctx.temp_types[i] != Type::Unknown)
+        {
+            return Err(format!("temp {} is mapped but also has type {:?}", i, ctx.temp_types[i]));
+        }
+
+        let mut context = Context {
+            stack_size,
+            sp_offset,
+            chain_depth_and_flags: ctx.chain_depth,
+            self_type: ctx.self_type,
+            ..Default::default()
+        };
+
+        for (i, &local_type) in ctx.local_types.iter().enumerate() {
+            context.local_types |= (local_type as u32) << (i * TYPE_BITS);
+        }
+
+        for (i, (&mapping, &temp_type)) in ctx.temp_mapping.iter().zip(ctx.temp_types.iter()).enumerate() {
+            let (kind, payload) = match mapping {
+                TempMapping::MapToStack => (MAP_TO_STACK, temp_type as u32),
+                TempMapping::MapToSelf => (MAP_TO_SELF, 0),
+                TempMapping::MapToLocal(local) => (MAP_TO_LOCAL, local as u32),
+            };
+            context.temp_mapping_kind |= kind << (i * KIND_BITS);
+            context.temp_payload |= payload << (i * TYPE_BITS);
+        }
+
+        Ok(context)
+    }
+}
+
+// The strategies only see contexts that passed holds() below
+impl From<initial_context::Context> for Context {
+    fn from(ctx: initial_context::Context) -> Self {
+        Context::try_from(&ctx).unwrap_or_else(|err| panic!("context doesn't fit the upstream layout: {}", err))
+    }
+}
+
+// reg_temps, the flags and inline_block have no equivalent and are dropped
+impl From<Context> for initial_context::Context {
+    fn from(context: Context) -> Self {
+        let mut ctx = initial_context::Context {
+            stack_size: context.stack_size as u16,
+            sp_offset: context.sp_offset as i16,
+            chain_depth: context.chain_depth_and_flags & CHAIN_DEPTH_MAX,
+            self_type: context.self_type,
+            ..Default::default()
+        };
+        for i in 0..MAX_LOCAL_TYPES {
+            ctx.local_types[i] = context.local_type(i);
+        }
+        for i in 0..MAX_TEMP_TYPES {
+            (ctx.temp_mapping[i], ctx.temp_types[i]) = context.temp_mapping(i);
+        }
+        ctx
+    }
+}
+
+impl ContextSize for Context {
+    type Context = Context;
+    type Pointer = Context;
+    type Storage = ();
+
+    fn get_storage(&self) -> Option<()> {
+        None
+    }
+
+    fn store_context(&mut self, context: Context) -> Context {
+        context
+    }
+
+    fn load_context(&self, pointer: &Context) -> initial_context::Context {
+        (*pointer).into()
+    }
+
+    fn holds(context: &initial_context::Context) -> bool {
+        Context::try_from(context).is_ok()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arena::ArenaStorage;
+
+    #[test]
+    fn contexts_that_dont_fit_are_rejected() {
+        let deep = initial_context::Context { stack_size: 256, ..Default::default() };
+        assert!(Context::try_from(&deep).is_err());
+
+        let chained = initial_context::Context { chain_depth: CHAIN_DEPTH_MAX + 1, ..Default::default() };
+        assert!(Context::try_from(&chained).is_err());
+
+        let mut mapped = initial_context::Context::default();
+        mapped.temp_mapping[2] = TempMapping::MapToSelf;
+        mapped.temp_types[2] = Type::Fixnum;
+        assert!(Context::try_from(&mapped).is_err());
+
+        for ctx in [&deep, &chained, &mapped] {
+            assert!(!Context::holds(ctx));
+            assert!(!ArenaStorage::<Context>::holds(ctx));
+        }
+        assert!(Context::holds(&initial_context::Context::default()));
+    }
+}